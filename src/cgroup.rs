@@ -0,0 +1,185 @@
+use std::{
+    fs::{self, OpenOptions},
+    io,
+    os::unix::{io::AsRawFd, process::CommandExt},
+    path::PathBuf,
+    process::Command,
+};
+
+use tracing::{debug, instrument, warn};
+
+use crate::config::ProgramConfig;
+
+// Control groups (cgroup v2)
+// ==========================
+
+/// A cgroup v2 directory holding the processes of a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+// Limits of program `p`, as pairs of cgroup file and controller.
+fn limits(p: &ProgramConfig) -> Vec<(&'static str, &'static str, &str)> {
+    [
+        ("memory.max", "memory", &p.memory_max),
+        ("cpu.max", "cpu", &p.cpu_max),
+        ("pids.max", "pids", &p.pids_max),
+    ]
+    .into_iter()
+    .filter_map(|(file, controller, value)| {
+        value.as_deref().map(|v| (file, controller, v))
+    })
+    .collect()
+}
+
+/// Return `true` if program `p` sets any cgroup limit.
+pub fn has_limits(p: &ProgramConfig) -> bool {
+    !limits(p).is_empty()
+}
+
+impl Cgroup {
+    /// Return the cgroup of program `p`, if it has one.
+    pub fn of(p: &ProgramConfig) -> Option<Self> {
+        p.cgroup.as_ref().map(|path| Cgroup { path: path.clone() })
+    }
+
+    /// Create the cgroup, if needed, and apply the limits of program `p`.
+    #[instrument(level = "debug", skip(p))]
+    pub fn prepare(&self, p: &ProgramConfig) -> io::Result<()> {
+        fs::create_dir_all(&self.path)?;
+        let limits = limits(p);
+        if let Some(parent) = self.path.parent() {
+            for (_, controller, _) in &limits {
+                let control = parent.join("cgroup.subtree_control");
+                fs::write(control, format!("+{controller}")).unwrap_or_else(
+                    |e| {
+                        warn!(
+                            controller,
+                            error = ?e,
+                            "cannot enable controller in parent cgroup"
+                        );
+                    },
+                );
+            }
+        }
+        for (file, _, value) in limits {
+            debug!(file, value, "applying cgroup limit");
+            fs::write(self.path.join(file), value)?;
+        }
+        Ok(())
+    }
+
+    /// Move the child spawned by `cmd` into the cgroup, right before `exec`.
+    /// Call it before the child drops its privileges, which the move may need.
+    pub fn attach(&self, cmd: &mut Command) -> io::Result<()> {
+        let procs = OpenOptions::new()
+            .write(true)
+            .open(self.path.join("cgroup.procs"))?;
+        unsafe {
+            cmd.pre_exec(move || {
+                // Writing "0" moves the writing process.
+                let n = libc::write(procs.as_raw_fd(), b"0".as_ptr().cast(), 1);
+                if n < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Pids of the processes in the cgroup.
+    pub fn procs(&self) -> io::Result<Vec<i32>> {
+        match fs::read_to_string(self.path.join("cgroup.procs")) {
+            Ok(s) => {
+                Ok(s.lines().filter_map(|l| l.trim().parse().ok()).collect())
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Number of processes killed by the OOM killer since the cgroup was
+    /// created. The cgroup outlives the children, so compare the number with
+    /// the one of an earlier time.
+    pub fn oom_kills(&self) -> io::Result<u64> {
+        let events = fs::read_to_string(self.path.join("memory.events"))?;
+        Ok(events
+            .lines()
+            .filter_map(|l| l.split_once(' '))
+            .find(|(key, _)| *key == "oom_kill")
+            .and_then(|(_, value)| value.trim().parse().ok())
+            .unwrap_or(0))
+    }
+
+    /// Kill every process in the cgroup.
+    ///
+    /// Uses `cgroup.kill` when the kernel supports it (Linux 5.14+), and
+    /// signals each process in `cgroup.procs` otherwise.
+    #[instrument(level = "debug")]
+    pub fn kill_all(&self) -> io::Result<()> {
+        if fs::write(self.path.join("cgroup.kill"), "1").is_ok() {
+            return Ok(());
+        }
+        for pid in self.procs()? {
+            debug!(pid, "killing process in cgroup");
+            unsafe {
+                libc::kill(pid, libc::SIGKILL);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Cgroup;
+    use crate::config::ProgramConfig;
+    use anyhow::Result;
+    use std::{fs, process::Command};
+
+    // A plain directory stands in for the cgroup filesystem in these tests.
+
+    #[test]
+    fn test_prepare_and_attach() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let p = ProgramConfig {
+            name: "sleep".into(),
+            cgroup: Some(root.path().join("sleep")),
+            memory_max: Some("64M".into()),
+            pids_max: Some("10".into()),
+            ..Default::default()
+        };
+        let cgroup = Cgroup::of(&p).unwrap();
+        cgroup.prepare(&p)?;
+        let read = |f| fs::read_to_string(cgroup.path.join(f));
+        assert_eq!(read("memory.max")?, "64M");
+        assert_eq!(read("pids.max")?, "10");
+        assert!(read("cpu.max").is_err());
+        assert!(root.path().join("cgroup.subtree_control").exists());
+
+        fs::write(cgroup.path.join("cgroup.procs"), "")?;
+        let mut cmd = Command::new("true");
+        cgroup.attach(&mut cmd)?;
+        assert!(cmd.status()?.success());
+        assert_eq!(read("cgroup.procs")?, "0");
+        Ok(())
+    }
+
+    #[test]
+    fn test_oom_kills() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let p = ProgramConfig {
+            cgroup: Some(root.path().into()),
+            ..Default::default()
+        };
+        let cgroup = Cgroup::of(&p).unwrap();
+        fs::write(
+            root.path().join("memory.events"),
+            "low 0\nhigh 0\nmax 3\noom 1\noom_kill 2\n",
+        )?;
+        assert_eq!(cgroup.oom_kills()?, 2);
+        Ok(())
+    }
+}
@@ -1,4 +1,10 @@
+use crate::cgroup;
 use crate::errors::SupersError;
+use crate::events;
+use crate::notifications;
+use crate::process;
+use crate::schedule::Schedule;
+use crate::watch;
 use config::Config;
 use globwalk::GlobWalkerBuilder;
 use serde::Serialize;
@@ -6,7 +12,11 @@ use serde_derive::Deserialize;
 use std::env;
 use std::net::Ipv4Addr;
 use std::path::{Path, PathBuf};
-use std::{collections::HashMap, net::IpAddr};
+use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+};
 use tracing::{instrument, debug};
 
 // Configuration management
@@ -44,12 +54,211 @@ pub enum RestartPolicy {
     Always,
     /// Never restart the program, regardless of exist status
     Never,
-    /// Restart the program if it exited with a non-success status, otherwise, do not restart
+    /// Restart the program if it exited with a non-success status, otherwise, do not restart;
+    /// see `restart_on_exit_codes`, `no_restart_on_exit_codes` and `restart_on_signals`
     OnError,
 }
 
+/// Identity of a replica of a program with `replicas` set
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Replica {
+    /// Name of the replicated program
+    pub of: String,
+    /// Index of the replica, from 0 to `replicas - 1`
+    pub index: u32,
+}
+
+/// These are the available kinds of programs
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgramKind {
+    /// A long-running program
+    #[default]
+    Service,
+    /// A task that runs once to completion; a successful run is reported as `Completed` and
+    /// is not restarted
+    Oneshot,
+}
+
+/// Source of the value of a secret environment variable
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct SecretSource {
+    /// File holding the value; a trailing newline is removed
+    pub file: PathBuf,
+}
+
+/// What to do when a scheduled run is due while the previous run is still going
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, Default)]
+pub enum Concurrency {
+    /// Skip the run
+    #[default]
+    Skip,
+    /// Start the run when the previous one finishes
+    Queue,
+}
+
+/// Where the stdin of a program comes from
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StdinMode {
+    /// The stdin of supers
+    #[default]
+    Inherit,
+    /// An empty input
+    Null,
+    /// A pipe that the API writes to
+    Pipe,
+}
+
+/// A command run by supers at a transition of a program
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct HookConfig {
+    /// The command to run; it gets the environment, working directory and user of the program
+    pub cmd: String,
+    /// An array of arguments to the command
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Seconds to wait for the command to finish before killing it
+    #[serde(default = "default_hook_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_hook_timeout() -> u64 {
+    30
+}
+
+/// Delays between restarts of a program that keeps failing
+///
+/// After `n` consecutive failures, the restart is delayed by `initial_ms * 2^(n - 1)`
+/// milliseconds, up to `max_ms`. A successful exit resets the count.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Backoff {
+    /// Delay after the first failure; 0 disables the backoff
+    #[serde(default)]
+    pub initial_ms: u64,
+    /// Maximum delay
+    #[serde(default = "default_backoff_max")]
+    pub max_ms: u64,
+}
+
+fn default_backoff_max() -> u64 {
+    60_000
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial_ms: 0,
+            max_ms: default_backoff_max(),
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay before restarting a program after `failures` consecutive failures.
+    pub fn delay(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return Duration::ZERO;
+        }
+        let factor = 2u64.saturating_pow(failures - 1);
+        Duration::from_millis(
+            self.initial_ms.saturating_mul(factor).min(self.max_ms),
+        )
+    }
+}
+
+/// These are the available ways of stopping a program
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, Default)]
+pub enum KillMode {
+    /// Run the program in its own process group and kill the whole group, plus every
+    /// process in the program's cgroup, if it has one
+    #[default]
+    ProcessGroup,
+    /// Kill only the direct child process
+    Process,
+}
+
+/// Value of a resource limit: a number, or `"unlimited"`
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum RlimitValue {
+    Limited(u64),
+    Unlimited,
+}
+
+impl std::fmt::Display for RlimitValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RlimitValue::Limited(n) => write!(f, "{n}"),
+            RlimitValue::Unlimited => write!(f, "unlimited"),
+        }
+    }
+}
+
+impl Serialize for RlimitValue {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self {
+            RlimitValue::Limited(n) => s.serialize_u64(*n),
+            RlimitValue::Unlimited => s.serialize_str("unlimited"),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for RlimitValue {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Number(u64),
+            Word(String),
+        }
+        match Raw::deserialize(d)? {
+            Raw::Number(n) => Ok(RlimitValue::Limited(n)),
+            Raw::Word(w) if w == "unlimited" => Ok(RlimitValue::Unlimited),
+            Raw::Word(w) => Err(serde::de::Error::custom(format!(
+                "invalid resource limit {w:?}; expected a number or \"unlimited\""
+            ))),
+        }
+    }
+}
+
+/// Soft and hard values of a resource limit
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Rlimit {
+    /// The value enforced by the kernel
+    pub soft: RlimitValue,
+    /// The ceiling for the soft value
+    pub hard: RlimitValue,
+}
+
+/// Resource limits for a program and its hooks, applied with `setrlimit` right
+/// before `exec`. Limits that are not set are inherited from supers.
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct Rlimits {
+    /// Maximum number of open file descriptors
+    #[serde(default)]
+    pub nofile: Option<Rlimit>,
+    /// Maximum number of processes for the real user id
+    #[serde(default)]
+    pub nproc: Option<Rlimit>,
+    /// Maximum size of a core file, in bytes
+    #[serde(default)]
+    pub core: Option<Rlimit>,
+    /// Maximum size of the virtual memory, in bytes
+    #[serde(default)]
+    pub r#as: Option<Rlimit>,
+    /// CPU time limit, in seconds
+    #[serde(default)]
+    pub cpu: Option<Rlimit>,
+    /// Maximum size of files created by the program, in bytes
+    #[serde(default)]
+    pub fsize: Option<Rlimit>,
+    /// Maximum size of the stack, in bytes
+    #[serde(default)]
+    pub stack: Option<Rlimit>,
+}
+
 /// Configuration for a program to be launched and supervised by supers.
-#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct ProgramConfig {
     /// The name of the program, used for naming the thread, logging, etc. Should be unique within a supers application
     pub name: String,
@@ -57,10 +266,305 @@ pub struct ProgramConfig {
     pub cmd: String,
     /// An array of arguments to the program's command.
     pub args: Vec<String>,
-    /// The environment variables to set before starting the program, as key-value pairs
+    /// The environment variables to set before starting the program, as key-value pairs.
+    /// Values can refer to other variables with `${VAR}` or `${VAR:-default}`
     pub env: HashMap<String, String>,
+    /// Dotenv files with environment variables for the program, read on every start.
+    /// Variables in `env` override the ones from these files
+    #[serde(default)]
+    pub env_files: Vec<PathBuf>,
+    /// Whether the program inherits the environment of supers
+    #[serde(default = "default_inherit_env")]
+    pub inherit_env: bool,
+    /// Environment variables whose values are read from files when the program starts.
+    /// Their values never show up in logs or API responses, and override all other variables
+    #[serde(default)]
+    pub secret_env: HashMap<String, SecretSource>,
+    /// Number of instances of the program, named `<name>-0` to `<name>-<N-1>`. Each one gets
+    /// its index in `SUPERS_REPLICA_INDEX`, and `{{...}}` expressions of `index` in `args`
+    /// and `env` are evaluated per replica (e.g., `"--port={{8000 + index}}"`). An explicit
+    /// `cgroup` gets the suffix `-<index>`, and depending on the program means depending
+    /// on all its replicas
+    #[serde(default)]
+    pub replicas: Option<u32>,
+    /// Set on the instances of a program with `replicas`
+    #[serde(skip)]
+    pub replica: Option<Replica>,
+    /// Group of programs, controlled together through the `/groups/{group}` endpoints
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Labels of the program, for selecting programs in the API (e.g., `tier = "web"`)
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// The kind of program
+    #[serde(default)]
+    pub kind: ProgramKind,
+    /// Oneshot programs that must complete successfully before this program is spawned
+    #[serde(default)]
+    pub depends_on: Vec<String>,
     /// The RestartPolicy for the program
     pub restartpolicy: RestartPolicy,
+    /// Exit codes that restart the program under the `OnError` policy, even 0; when set,
+    /// other non-zero exit codes do not restart it
+    #[serde(default)]
+    pub restart_on_exit_codes: Vec<i32>,
+    /// Exit codes that are expected and never restart the program, under any policy
+    #[serde(default)]
+    pub no_restart_on_exit_codes: Vec<i32>,
+    /// Signals (e.g., "SIGKILL" or 9) that restart the program under the `OnError` policy
+    /// when they kill it; when set, other signals do not restart it
+    #[serde(default)]
+    pub restart_on_signals: Vec<String>,
+    /// Delays between restarts after failures
+    #[serde(default)]
+    pub backoff: Backoff,
+    /// Cron-style schedule (e.g., "*/5 * * * *", in UTC) for running the program periodically.
+    /// Scheduled programs are not started with the application, and successful runs are
+    /// not restarted
+    #[serde(default)]
+    pub schedule: Option<String>,
+    /// What to do when a scheduled run is due while the previous run is still going
+    #[serde(default)]
+    pub concurrency: Concurrency,
+    /// Command to run before spawning the program; if it fails, the program is not spawned
+    /// and the failure counts toward the backoff
+    #[serde(default)]
+    pub pre_start: Option<HookConfig>,
+    /// Command to run after spawning the program
+    #[serde(default)]
+    pub post_start: Option<HookConfig>,
+    /// Command to run before stopping the program
+    #[serde(default)]
+    pub pre_stop: Option<HookConfig>,
+    /// Command to run after the program stops or exits
+    #[serde(default)]
+    pub post_stop: Option<HookConfig>,
+    /// The working directory of the program; defaults to the working directory of supers
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+    /// The user (name or numeric id) to run the program as; requires supers to run as root
+    #[serde(default)]
+    pub user: Option<String>,
+    /// The group (name or numeric id) to run the program as; defaults to the primary group of `user`
+    #[serde(default)]
+    pub run_as_group: Option<String>,
+    /// Supplementary groups (names or numeric ids) for the program
+    #[serde(default)]
+    pub supplementary_groups: Vec<String>,
+    /// The file mode creation mask for the program, in octal (e.g., "022")
+    #[serde(default)]
+    pub umask: Option<String>,
+    /// Resource limits for the program
+    #[serde(default)]
+    pub rlimits: Rlimits,
+    /// The cgroup v2 directory of the program; defaults to `<cgroup_root>/<name>` when the
+    /// application sets `cgroup_root`
+    #[serde(default)]
+    pub cgroup: Option<PathBuf>,
+    /// Value for `memory.max` in the program's cgroup (e.g., "512M" or "max")
+    #[serde(default)]
+    pub memory_max: Option<String>,
+    /// Value for `cpu.max` in the program's cgroup (e.g., "50000 100000")
+    #[serde(default)]
+    pub cpu_max: Option<String>,
+    /// Value for `pids.max` in the program's cgroup (e.g., "100")
+    #[serde(default)]
+    pub pids_max: Option<String>,
+    /// How to stop the program
+    #[serde(default)]
+    pub kill_mode: KillMode,
+    /// The stdin of the program; with "pipe", `POST /programs/{name}/stdin` writes to it
+    #[serde(default)]
+    pub stdin: StdinMode,
+    /// Signals that the API can send to the program; when empty, all signals not in
+    /// `denied_signals` are allowed
+    #[serde(default)]
+    pub allowed_signals: Vec<String>,
+    /// Signals that the API cannot send to the program
+    #[serde(default)]
+    pub denied_signals: Vec<String>,
+    /// Paths or globs (e.g., "config/*.yaml") relative to the working directory of supers;
+    /// the program restarts when they change, if it is running
+    #[serde(default)]
+    pub watch: Vec<String>,
+    /// Time without changes to the watched paths before restarting the program
+    #[serde(default = "default_watch_debounce_ms")]
+    pub watch_debounce_ms: u64,
+    /// Make the program an event listener, fed with the lifecycle events of the
+    /// programs
+    #[serde(default)]
+    pub listener: Option<ListenerConfig>,
+    /// Number of runs of the program kept in its history
+    #[serde(default = "default_history_size")]
+    pub history_size: usize,
+}
+
+impl ProgramConfig {
+    /// Return `true` if the API can send `signal` to the program.
+    pub fn signal_allowed(&self, signal: i32) -> bool {
+        let listed = |signals: &[String]| {
+            signals
+                .iter()
+                .any(|s| process::parse_signal(s) == Some(signal))
+        };
+        let allowed = &self.allowed_signals;
+        !listed(&self.denied_signals)
+            && (allowed.is_empty() || listed(allowed))
+    }
+}
+
+fn default_inherit_env() -> bool {
+    true
+}
+
+fn default_watch_debounce_ms() -> u64 {
+    500
+}
+
+fn default_history_size() -> usize {
+    100
+}
+
+impl Default for ProgramConfig {
+    fn default() -> Self {
+        Self {
+            name: Default::default(),
+            cmd: Default::default(),
+            args: Default::default(),
+            env: Default::default(),
+            env_files: Default::default(),
+            inherit_env: default_inherit_env(),
+            secret_env: Default::default(),
+            replicas: Default::default(),
+            replica: Default::default(),
+            group: Default::default(),
+            labels: Default::default(),
+            kind: Default::default(),
+            depends_on: Default::default(),
+            restartpolicy: Default::default(),
+            restart_on_exit_codes: Default::default(),
+            no_restart_on_exit_codes: Default::default(),
+            restart_on_signals: Default::default(),
+            backoff: Default::default(),
+            schedule: Default::default(),
+            concurrency: Default::default(),
+            pre_start: Default::default(),
+            post_start: Default::default(),
+            pre_stop: Default::default(),
+            post_stop: Default::default(),
+            cwd: Default::default(),
+            user: Default::default(),
+            run_as_group: Default::default(),
+            supplementary_groups: Default::default(),
+            umask: Default::default(),
+            rlimits: Default::default(),
+            cgroup: Default::default(),
+            memory_max: Default::default(),
+            cpu_max: Default::default(),
+            pids_max: Default::default(),
+            kill_mode: Default::default(),
+            stdin: Default::default(),
+            allowed_signals: Default::default(),
+            denied_signals: Default::default(),
+            watch: Default::default(),
+            watch_debounce_ms: default_watch_debounce_ms(),
+            listener: Default::default(),
+            history_size: default_history_size(),
+        }
+    }
+}
+
+/// Events fed to a program acting as an event listener
+///
+/// The listener reads one event per line, as JSON, on its stdin, and answers
+/// each one with a line on its stdout: `OK` if it handled the event, or
+/// anything else if it failed to.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct ListenerConfig {
+    /// Names of the events fed to the listener; all if empty
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Programs whose events are fed to the listener; all if empty
+    #[serde(default)]
+    pub programs: Vec<String>,
+    /// Time the listener has to answer an event before it is restarted
+    #[serde(default = "default_ack_timeout_secs")]
+    pub ack_timeout_secs: u64,
+}
+
+fn default_ack_timeout_secs() -> u64 {
+    10
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            events: Default::default(),
+            programs: Default::default(),
+            ack_timeout_secs: default_ack_timeout_secs(),
+        }
+    }
+}
+
+/// Webhook notified of the lifecycle events of the programs
+///
+/// Each matching event is sent in a `POST` request to `url`, with the event as
+/// JSON or rendered with `template`.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct NotificationConfig {
+    pub url: String,
+    /// Names of the events to notify (e.g., `fatal` or `exited`); all if empty
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Programs whose events are notified; all if empty
+    #[serde(default)]
+    pub programs: Vec<String>,
+    /// Notify only the exits that were not requested and not successful
+    #[serde(default)]
+    pub only_errors: bool,
+    /// Payload with `{{field}}` placeholders for the fields of the event, or
+    /// `{{json}}` for the whole event
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Number of times a failed request is retried
+    #[serde(default = "default_notification_retries")]
+    pub retries: u32,
+    /// Delays between retries
+    #[serde(default = "default_notification_backoff")]
+    pub backoff: Backoff,
+    /// Timeout of each request
+    #[serde(default = "default_notification_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_notification_retries() -> u32 {
+    3
+}
+
+fn default_notification_backoff() -> Backoff {
+    Backoff {
+        initial_ms: 1000,
+        max_ms: 30_000,
+    }
+}
+
+fn default_notification_timeout_secs() -> u64 {
+    10
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self {
+            url: Default::default(),
+            events: Default::default(),
+            programs: Default::default(),
+            only_errors: false,
+            template: None,
+            retries: default_notification_retries(),
+            backoff: default_notification_backoff(),
+            timeout_secs: default_notification_timeout_secs(),
+        }
+    }
 }
 
 /// Configuration for the application iteself
@@ -75,6 +579,27 @@ pub struct ApplicationConfig {
     /// The programs comprising the application
     #[serde(default)]
     pub programs: Vec<ProgramConfig>,
+    /// A delegated cgroup v2 subtree; when set, each program runs in its own cgroup below it
+    #[serde(default)]
+    pub cgroup_root: Option<PathBuf>,
+    /// Directory where supers keeps the state of the programs across restarts
+    #[serde(default)]
+    pub state_dir: Option<PathBuf>,
+    /// Webhooks notified of the lifecycle events of the programs
+    #[serde(default)]
+    pub notifications: Vec<NotificationConfig>,
+    /// Run as the init of a container: reap orphans, forward signals to the
+    /// children and exit when the application stops. Always on as PID 1
+    #[serde(default)]
+    pub init: bool,
+    /// Seconds the children have to exit after a termination signal, in init
+    /// mode, before they are killed
+    #[serde(default = "default_stop_timeout_secs")]
+    pub stop_timeout_secs: u64,
+}
+
+fn default_stop_timeout_secs() -> u64 {
+    10
 }
 
 impl Default for ApplicationConfig {
@@ -84,10 +609,162 @@ impl Default for ApplicationConfig {
             address: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
             port: 8080,
             programs: Default::default(),
+            cgroup_root: Default::default(),
+            state_dir: Default::default(),
+            notifications: Default::default(),
+            init: Default::default(),
+            stop_timeout_secs: default_stop_timeout_secs(),
         }
     }
 }
 
+// Evaluate an integer expression made of numbers, `index`, `+`, `-` and `*`.
+fn eval_index_expr(expr: &str, index: i64) -> Option<i64> {
+    let (mut sum, mut product, mut sign) = (0i64, 1i64, 1i64);
+    let mut expect_operand = true;
+    let mut rest = expr.trim();
+    while !rest.is_empty() {
+        if expect_operand {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            let (token, r) = rest.split_at(end);
+            let value = match token {
+                "index" => index,
+                _ => token.parse().ok()?,
+            };
+            product = product.checked_mul(value)?;
+            rest = r;
+        } else {
+            let (op, r) = rest.split_at(1);
+            match op {
+                "*" => {}
+                "+" | "-" => {
+                    sum = sum.checked_add(sign * product)?;
+                    product = 1;
+                    sign = if op == "+" { 1 } else { -1 };
+                }
+                _ => return None,
+            }
+            rest = r;
+        }
+        rest = rest.trim_start();
+        expect_operand = !expect_operand;
+    }
+    (!expect_operand).then(|| sum.checked_add(sign * product))?
+}
+
+/// Replace the `{{...}}` expressions in `s` with their value for replica `index`.
+pub fn render_replica_template(s: &str, index: u32) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("{{") {
+        let len = rest[start..]
+            .find("}}")
+            .ok_or_else(|| format!("unterminated expression in {s:?}"))?;
+        let expr = &rest[start + 2..start + len];
+        let value = eval_index_expr(expr, index.into())
+            .ok_or_else(|| format!("invalid expression {expr:?} in {s:?}"))?;
+        out.push_str(&rest[..start]);
+        out.push_str(&value.to_string());
+        rest = &rest[start + len + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// Find a cycle in `deps`, the dependencies of each program, through program
+// `name`, whose dependents are on `path`. Programs in `checked` are known to
+// be outside of any cycle. Return the names along the cycle.
+fn find_cycle<'a>(
+    name: &'a str,
+    deps: &HashMap<&'a str, &'a [String]>,
+    path: &mut Vec<&'a str>,
+    checked: &mut HashSet<&'a str>,
+) -> Option<Vec<String>> {
+    if let Some(i) = path.iter().position(|n| *n == name) {
+        let mut cycle: Vec<String> =
+            path[i..].iter().map(|n| n.to_string()).collect();
+        cycle.push(name.to_string());
+        return Some(cycle);
+    }
+    if checked.contains(name) {
+        return None;
+    }
+    path.push(name);
+    for dep in deps.get(name).copied().unwrap_or_default() {
+        if let Some(cycle) = find_cycle(dep, deps, path, checked) {
+            return Some(cycle);
+        }
+    }
+    path.pop();
+    checked.insert(name);
+    None
+}
+
+// Expand program `p` into its replicas.
+fn replicate(p: ProgramConfig) -> Result<Vec<ProgramConfig>, SupersError> {
+    let Some(n) = p.replicas else {
+        return Ok(vec![p]);
+    };
+    if n == 0 {
+        return Err(SupersError::ApplicationConfigError(format!(
+            "program {} must have at least 1 replica",
+            p.name
+        )));
+    }
+    let err = |e| {
+        SupersError::ApplicationConfigError(format!(
+            "invalid replica template for program {}: {e}",
+            p.name
+        ))
+    };
+    (0..n)
+        .map(|index| {
+            let render = |s: &String| render_replica_template(s, index);
+            let args = p.args.iter().map(render).collect::<Result<_, _>>();
+            let mut env = p
+                .env
+                .iter()
+                .map(|(k, v)| render(v).map(|v| (k.clone(), v)))
+                .collect::<Result<HashMap<_, _>, _>>()
+                .map_err(err)?;
+            env.insert("SUPERS_REPLICA_INDEX".into(), index.to_string());
+            // Replicas never share an explicit cgroup, like they do not
+            // share a name.
+            let cgroup = p.cgroup.as_ref().map(|path| {
+                let mut path = path.clone().into_os_string();
+                path.push(format!("-{index}"));
+                PathBuf::from(path)
+            });
+            Ok(ProgramConfig {
+                name: format!("{}-{index}", p.name),
+                args: args.map_err(err)?,
+                env,
+                cgroup,
+                replicas: None,
+                replica: Some(Replica {
+                    of: p.name.clone(),
+                    index,
+                }),
+                ..p.clone()
+            })
+        })
+        .collect()
+}
+
+/// Parse a label selector of comma-separated `key=value` pairs, such as
+/// `"tier=web,env=prod"`.
+pub fn parse_label_selector(s: &str) -> Option<Vec<(String, String)>> {
+    s.split(',')
+        .map(|pair| {
+            let (k, v) = pair.split_once('=')?;
+            let k = k.trim();
+            (!k.is_empty()).then(|| (k.to_string(), v.trim().to_string()))
+        })
+        .collect()
+}
+
 // Get first file matching `pattern` in `dir`, or `None` otherwise.
 fn get_first_match(pattern: &str, dir: &Path) -> Option<PathBuf> {
     GlobWalkerBuilder::new(dir, pattern)
@@ -170,13 +847,150 @@ impl ApplicationConfig {
             .build()
             .and_then(|s| s.try_deserialize::<ApplicationConfig>())
             .map_err(|e| SupersError::ApplicationConfigError(format!("{}", e)))
+            .and_then(|c| c.with_replicas())
+            .map(|c| c.with_cgroups())
+            .and_then(|c| c.validate())
+    }
+
+    // Replace the programs with `replicas` set by their replicas, also in
+    // the dependencies of the programs.
+    fn with_replicas(mut self) -> Result<Self, SupersError> {
+        let mut programs = vec![];
+        for p in self.programs {
+            programs.extend(replicate(p)?);
+        }
+        self.programs = programs;
+        let sets = self.replica_sets();
+        for p in &mut self.programs {
+            p.depends_on = p
+                .depends_on
+                .iter()
+                .flat_map(|dep| {
+                    sets.get(dep).cloned().unwrap_or_else(|| vec![dep.clone()])
+                })
+                .collect();
+        }
+        Ok(self)
+    }
+
+    /// Names of the replicas of each program with `replicas` set.
+    pub fn replica_sets(&self) -> HashMap<String, Vec<String>> {
+        let mut sets: HashMap<String, Vec<String>> = HashMap::new();
+        for p in &self.programs {
+            if let Some(replica) = &p.replica {
+                sets.entry(replica.of.clone())
+                    .or_default()
+                    .push(p.name.clone());
+            }
+        }
+        sets
+    }
+
+    /// Names of the programs in each group. The replicas of a program form a group
+    /// named after it, and `group` adds a program to a group.
+    pub fn groups(&self) -> HashMap<String, Vec<String>> {
+        let mut groups = self.replica_sets();
+        for p in &self.programs {
+            if let Some(group) = &p.group {
+                groups.entry(group.clone()).or_default().push(p.name.clone());
+            }
+        }
+        groups
+    }
+
+    // Place programs without an explicit cgroup below `cgroup_root`, if set.
+    fn with_cgroups(mut self) -> Self {
+        if let Some(root) = &self.cgroup_root {
+            for p in self.programs.iter_mut().filter(|p| p.cgroup.is_none()) {
+                p.cgroup = Some(root.join(&p.name));
+            }
+        }
+        self
+    }
+
+    // Check that supers is able to run the configured programs as specified.
+    fn validate(self) -> Result<Self, SupersError> {
+        // The replicas of a program are also addressed by its name.
+        let sets = self.replica_sets();
+        let mut names = HashSet::new();
+        for name in self.programs.iter().map(|p| &p.name).chain(sets.keys()) {
+            if !names.insert(name) {
+                return Err(SupersError::ApplicationConfigError(format!(
+                    "more than one program is named {name}"
+                )));
+            }
+        }
+        let deps = self
+            .programs
+            .iter()
+            .map(|p| (p.name.as_str(), p.depends_on.as_slice()))
+            .collect();
+        let mut checked = HashSet::new();
+        for p in &self.programs {
+            if let Some(cycle) =
+                find_cycle(&p.name, &deps, &mut vec![], &mut checked)
+            {
+                return Err(SupersError::ApplicationConfigError(format!(
+                    "programs depend on each other: {}",
+                    cycle.join(" -> ")
+                )));
+            }
+        }
+        for p in &self.programs {
+            process::validate(p)?;
+            watch::watched_paths(p)?;
+            if let Some(schedule) = &p.schedule {
+                Schedule::parse(schedule).map_err(|e| {
+                    SupersError::ApplicationConfigError(format!(
+                        "invalid schedule for program {}: {e}",
+                        p.name
+                    ))
+                })?;
+            }
+            for dep in &p.depends_on {
+                let is_oneshot = self
+                    .programs
+                    .iter()
+                    .any(|o| &o.name == dep && o.kind == ProgramKind::Oneshot);
+                if !is_oneshot {
+                    return Err(SupersError::ApplicationConfigError(format!(
+                        "program {} depends on {dep}, which is not a oneshot program",
+                        p.name
+                    )));
+                }
+            }
+            if let Some(listener) = &p.listener {
+                events::validate_names(&listener.events).map_err(|e| {
+                    SupersError::ProgramExecConfigError(p.name.clone(), e)
+                })?;
+                // The events are written to the stdin of listeners.
+                if p.stdin == StdinMode::Pipe {
+                    return Err(SupersError::ProgramExecConfigError(
+                        p.name.clone(),
+                        "a listener cannot have stdin = \"pipe\"".into(),
+                    ));
+                }
+            }
+            if p.cgroup.is_none() && cgroup::has_limits(p) {
+                return Err(SupersError::ProgramExecConfigError(
+                    p.name.clone(),
+                    "cgroup limits require a cgroup; set `cgroup` or `cgroup_root`"
+                        .into(),
+                ));
+            }
+        }
+        for n in &self.notifications {
+            notifications::validate(n)?;
+        }
+        Ok(self)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::get_first_match;
-    use super::ApplicationConfig;
+    use super::{parse_label_selector, render_replica_template};
+    use super::{ApplicationConfig, ProgramConfig, ProgramKind, StdinMode};
     use anyhow::Result;
     use std::env;
     use std::error::Error;
@@ -206,6 +1020,155 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_render_replica_template() {
+        let render = |s| render_replica_template(s, 2);
+        assert_eq!(render("--port={{8000 + index}}").unwrap(), "--port=8002");
+        assert_eq!(render("{{ index*10 - 1 }}-{{index}}").unwrap(), "19-2");
+        assert_eq!(render("plain").unwrap(), "plain");
+        assert!(render("{{index").is_err());
+        assert!(render("{{index +}}").is_err());
+        assert!(render("{{port}}").is_err());
+    }
+
+    #[test]
+    fn test_parse_label_selector() {
+        assert_eq!(
+            parse_label_selector("tier=web, env = prod"),
+            Some(vec![
+                ("tier".into(), "web".into()),
+                ("env".into(), "prod".into())
+            ])
+        );
+        assert_eq!(parse_label_selector("tier"), None);
+        assert_eq!(parse_label_selector("=web"), None);
+    }
+
+    #[test]
+    fn test_signal_allowed() {
+        let p = ProgramConfig::default();
+        assert!(p.signal_allowed(libc::SIGHUP));
+        let p = ProgramConfig {
+            allowed_signals: vec!["SIGHUP".into(), "USR1".into()],
+            denied_signals: vec!["SIGUSR1".into()],
+            ..Default::default()
+        };
+        assert!(p.signal_allowed(libc::SIGHUP));
+        assert!(!p.signal_allowed(libc::SIGUSR1));
+        assert!(!p.signal_allowed(libc::SIGKILL));
+    }
+
+    #[test]
+    fn test_replicas() -> Result<()> {
+        let cfg = ApplicationConfig {
+            programs: vec![
+                ProgramConfig {
+                    name: "worker".into(),
+                    args: vec!["--port".into(), "{{8000 + index}}".into()],
+                    replicas: Some(3),
+                    cgroup: Some("/sys/fs/cgroup/app/worker".into()),
+                    ..Default::default()
+                },
+                ProgramConfig {
+                    name: "single".into(),
+                    args: vec!["{{index}}".into()],
+                    depends_on: vec!["worker".into()],
+                    group: Some("web".into()),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        }
+        .with_replicas()?;
+        let names: Vec<_> = cfg.programs.iter().map(|p| &p.name).collect();
+        assert_eq!(names, ["worker-0", "worker-1", "worker-2", "single"]);
+        let worker = &cfg.programs[2];
+        assert_eq!(worker.args, ["--port", "8002"]);
+        assert_eq!(worker.env["SUPERS_REPLICA_INDEX"], "2");
+        assert_eq!(worker.replica.as_ref().unwrap().of, "worker");
+        assert_eq!(cfg.programs[3].args, ["{{index}}"]);
+        assert_eq!(cfg.replica_sets()["worker"].len(), 3);
+        assert!(!cfg.replica_sets().contains_key("single"));
+        assert_eq!(cfg.groups()["worker"], cfg.replica_sets()["worker"]);
+        assert_eq!(cfg.groups()["web"], ["single"]);
+        assert_eq!(
+            worker.cgroup,
+            Some(PathBuf::from("/sys/fs/cgroup/app/worker-2"))
+        );
+        // Depending on a replicated program means depending on its replicas
+        assert_eq!(cfg.programs[3].depends_on, cfg.replica_sets()["worker"]);
+
+        let cfg = ApplicationConfig {
+            programs: vec![ProgramConfig {
+                name: "worker".into(),
+                replicas: Some(0),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(cfg.with_replicas().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_listener() {
+        let listener = ProgramConfig {
+            name: "listener".into(),
+            listener: Some(Default::default()),
+            ..Default::default()
+        };
+        let cfg = |p: ProgramConfig| ApplicationConfig {
+            programs: vec![p],
+            ..Default::default()
+        };
+        assert!(cfg(listener.clone()).validate().is_ok());
+        let piped = ProgramConfig {
+            stdin: StdinMode::Pipe,
+            ..listener
+        };
+        assert!(cfg(piped).validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_names_and_dependencies() -> Result<()> {
+        let program = |name: &str, depends_on: &[&str]| ProgramConfig {
+            name: name.into(),
+            kind: ProgramKind::Oneshot,
+            depends_on: depends_on.iter().map(|d| d.to_string()).collect(),
+            ..Default::default()
+        };
+        let cfg = |programs: Vec<ProgramConfig>| {
+            ApplicationConfig {
+                programs,
+                ..Default::default()
+            }
+            .with_replicas()?
+            .validate()
+        };
+        assert!(cfg(vec![program("a", &[]), program("b", &["a"])]).is_ok());
+        assert!(cfg(vec![program("a", &[]), program("a", &[])]).is_err());
+
+        // Replicas collide with programs named like them, or like the
+        // program they replicate
+        let worker = ProgramConfig {
+            replicas: Some(2),
+            ..program("worker", &[])
+        };
+        for name in ["worker-1", "worker"] {
+            let err = cfg(vec![worker.clone(), program(name, &[])]);
+            assert!(err.unwrap_err().to_string().contains(name));
+        }
+
+        let err = cfg(vec![
+            program("a", &["c"]),
+            program("b", &["a"]),
+            program("c", &["b"]),
+        ]);
+        assert!(err.unwrap_err().to_string().contains("a -> c -> b -> a"));
+        assert!(cfg(vec![program("a", &["a"])]).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_default_config() -> Result<()> {
         let x = ApplicationConfig::from_sources_variable(
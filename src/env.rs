@@ -0,0 +1,348 @@
+use std::{collections::HashMap, fmt, fs};
+
+use tracing::{debug, instrument};
+
+use crate::{config::ProgramConfig, errors::SupersError};
+
+// Environment of child processes
+// ==============================
+
+/// Parse the contents of a dotenv file into an ordered list of key-value pairs.
+///
+/// Supports comments, an optional `export` prefix, and unquoted, single-quoted
+/// (literal) and double-quoted (with `\n`, `\t`, `\"` and `\\` escapes) values.
+/// Values of single-quoted entries are wrapped in `Literal` so they are not
+/// interpolated.
+pub fn parse_dotenv(contents: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut vars = vec![];
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected KEY=VALUE", n + 1))?;
+        let key = key.trim();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(format!("line {}: invalid key {key:?}", n + 1));
+        }
+        let value = parse_value(value.trim())
+            .ok_or_else(|| format!("line {}: unterminated quote", n + 1))?;
+        vars.push((key.to_string(), value));
+    }
+    Ok(vars)
+}
+
+/// A value read from a dotenv file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    /// A value subject to `${VAR}` interpolation
+    Template(String),
+    /// A value used as is
+    Literal(String),
+}
+
+// Parse the value of a dotenv entry; `None` if a quote is not terminated.
+fn parse_value(value: &str) -> Option<Value> {
+    if let Some(rest) = value.strip_prefix('\'') {
+        let end = rest.find('\'')?;
+        return Some(Value::Literal(rest[..end].to_string()));
+    }
+    if let Some(rest) = value.strip_prefix('"') {
+        let mut out = String::new();
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => return Some(Value::Template(out)),
+                '\\' => match chars.next()? {
+                    'n' => out.push('\n'),
+                    't' => out.push('\t'),
+                    c => out.push(c),
+                },
+                c => out.push(c),
+            }
+        }
+        return None;
+    }
+    let value = match value.find(" #") {
+        Some(i) => value[..i].trim_end(),
+        None => value,
+    };
+    Some(Value::Template(value.to_string()))
+}
+
+// Offset of the brace closing the reference at the start of `s`, which may
+// contain nested references in its default value.
+fn closing_brace(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 1 => return Some(i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Expand `${VAR}` and `${VAR:-default}` in `value`, looking up variables with
+/// `lookup`. The default applies when the variable is unset or empty.
+/// Unterminated references are kept as is.
+pub fn interpolate<F>(value: &str, lookup: &mut F) -> String
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = closing_brace(&rest[start..]) else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+        let reference = &rest[start + 2..start + len];
+        let (name, default) = match reference.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (reference, None),
+        };
+        let found = lookup(name).filter(|v| !v.is_empty() || default.is_none());
+        match (found, default) {
+            (Some(v), _) => out.push_str(&v),
+            (None, Some(default)) => {
+                out.push_str(&interpolate(default, lookup))
+            }
+            (None, None) => {}
+        }
+        rest = &rest[start + len + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// Resolves the variables of the `env` table of a program, which may refer to
+// each other in any order.
+struct Resolver<'a> {
+    raw: &'a HashMap<String, String>,
+    base: &'a HashMap<String, String>,
+    resolved: HashMap<String, String>,
+    // Keys being resolved, to break reference cycles.
+    resolving: Vec<String>,
+}
+
+impl Resolver<'_> {
+    fn lookup(&mut self, name: &str) -> Option<String> {
+        if let Some(v) = self.resolved.get(name) {
+            return Some(v.clone());
+        }
+        match self.raw.get(name) {
+            Some(_) if self.resolving.iter().any(|k| k == name) => {
+                // A reference cycle falls back to the outer definition.
+                self.base.get(name).cloned()
+            }
+            Some(raw) => {
+                self.resolving.push(name.to_string());
+                let v = interpolate(raw, &mut |n| self.lookup(n));
+                self.resolving.pop();
+                self.resolved.insert(name.to_string(), v.clone());
+                Some(v)
+            }
+            None => self.base.get(name).cloned(),
+        }
+    }
+}
+
+/// Build the variables to set in the child of program `p`, given the
+/// environment of supers, `supers_env`.
+///
+/// The variables come from, in order of precedence: the `env` table and the
+/// files in `env_files` (later files override earlier ones). Values can refer
+/// to variables from any of these sources, or to the environment of supers.
+/// The environment of supers itself, inherited when `inherit_env` is set, is
+/// left to the caller, since it may hold variables that are not UTF-8.
+#[instrument(level = "debug", skip_all, fields(program = p.name))]
+pub fn resolve_env(
+    p: &ProgramConfig,
+    supers_env: &HashMap<String, String>,
+) -> Result<HashMap<String, String>, SupersError> {
+    // Variables visible to interpolation.
+    let mut visible = supers_env.clone();
+    // Variables set in the child, on top of the inherited ones.
+    let mut vars = HashMap::new();
+    for file in &p.env_files {
+        debug!(file = ?file, "reading env file");
+        let contents = fs::read_to_string(file)
+            .map_err(|e| {
+                format!("cannot read env file {}: {e}", file.display())
+            })
+            .and_then(|c| {
+                parse_dotenv(&c).map_err(|e| {
+                    format!("cannot parse env file {}: {e}", file.display())
+                })
+            })
+            .map_err(|e| SupersError::ProgramEnvError(p.name.clone(), e))?;
+        for (key, value) in contents {
+            let value = match value {
+                Value::Literal(v) => v,
+                Value::Template(v) => {
+                    interpolate(&v, &mut |n| visible.get(n).cloned())
+                }
+            };
+            visible.insert(key.clone(), value.clone());
+            vars.insert(key, value);
+        }
+    }
+    let mut resolver = Resolver {
+        raw: &p.env,
+        base: &visible,
+        resolved: HashMap::new(),
+        resolving: vec![],
+    };
+    for key in p.env.keys() {
+        resolver.lookup(key);
+    }
+    vars.extend(resolver.resolved);
+    Ok(vars)
+}
+
+/// The value of a secret, which is redacted when formatted.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    /// The actual value of the secret.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[redacted]")
+    }
+}
+
+/// Read the values of the secret environment variables of program `p`.
+#[instrument(level = "debug", skip_all, fields(program = p.name))]
+pub fn read_secrets(
+    p: &ProgramConfig,
+) -> Result<HashMap<String, Secret>, SupersError> {
+    p.secret_env
+        .iter()
+        .map(|(key, source)| {
+            debug!(key, file = ?source.file, "reading secret");
+            fs::read_to_string(&source.file)
+                .map(|v| {
+                    let v = v.strip_suffix('\n').unwrap_or(&v);
+                    (key.clone(), Secret(v.to_string()))
+                })
+                .map_err(|e| {
+                    SupersError::ProgramEnvError(
+                        p.name.clone(),
+                        format!(
+                            "cannot read secret {key} from {}: {e}",
+                            source.file.display()
+                        ),
+                    )
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{interpolate, parse_dotenv, read_secrets, resolve_env, Value};
+    use crate::config::{ProgramConfig, SecretSource};
+    use anyhow::Result;
+    use std::{collections::HashMap, fs};
+
+    #[test]
+    fn test_parse_dotenv() -> Result<()> {
+        let vars = parse_dotenv(
+            "# comment\n\
+             A=1\n\
+             export B = two words # trailing\n\
+             C='${A} literal'\n\
+             D=\"line\\nnext \\\"q\\\"\"\n\
+             \n",
+        )
+        .unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                ("A".into(), Value::Template("1".into())),
+                ("B".into(), Value::Template("two words".into())),
+                ("C".into(), Value::Literal("${A} literal".into())),
+                ("D".into(), Value::Template("line\nnext \"q\"".into())),
+            ]
+        );
+        assert!(parse_dotenv("NOVALUE").is_err());
+        assert!(parse_dotenv("A=\"open").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let vars = HashMap::from([
+            ("A".to_string(), "1".to_string()),
+            ("E".to_string(), "".to_string()),
+        ]);
+        let mut lookup = |n: &str| vars.get(n).cloned();
+        assert_eq!(interpolate("x${A}y", &mut lookup), "x1y");
+        assert_eq!(interpolate("${B}", &mut lookup), "");
+        assert_eq!(interpolate("${B:-dflt}", &mut lookup), "dflt");
+        assert_eq!(interpolate("${E:-dflt}", &mut lookup), "dflt");
+        assert_eq!(interpolate("${E}", &mut lookup), "");
+        assert_eq!(interpolate("${B:-${A}}", &mut lookup), "1");
+        assert_eq!(interpolate("a ${A", &mut lookup), "a ${A");
+    }
+
+    #[test]
+    fn test_resolve_env() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let file = temp_dir.path().join(".env");
+        fs::write(&file, "HOST=db\nURL=postgres://${HOST}:${PORT:-5432}\n")?;
+        let supers_env =
+            HashMap::from([("OUTER".into(), "from-supers".into())]);
+        let p = ProgramConfig {
+            env_files: vec![file],
+            env: HashMap::from([
+                ("HOST".into(), "${OTHER}".into()),
+                ("OTHER".into(), "db2".into()),
+                ("INNER".into(), "${OUTER}".into()),
+                ("LOOP".into(), "${LOOP}x".into()),
+            ]),
+            inherit_env: false,
+            ..Default::default()
+        };
+        let vars = resolve_env(&p, &supers_env)?;
+        assert_eq!(vars["URL"], "postgres://db:5432");
+        assert_eq!(vars["HOST"], "db2");
+        assert_eq!(vars["INNER"], "from-supers");
+        assert_eq!(vars["LOOP"], "x");
+        assert!(!vars.contains_key("OUTER"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_secrets() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let file = temp_dir.path().join("db");
+        fs::write(&file, "hunter2\n")?;
+        let p = ProgramConfig {
+            secret_env: HashMap::from([(
+                "DB_PASS".into(),
+                SecretSource { file: file.clone() },
+            )]),
+            ..Default::default()
+        };
+        let secrets = read_secrets(&p)?;
+        assert_eq!(secrets["DB_PASS"].expose(), "hunter2");
+        assert!(!format!("{:?}", secrets).contains("hunter2"));
+
+        fs::remove_file(&file)?;
+        assert!(read_secrets(&p).is_err());
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 use crossbeam::channel::SendError;
 use thiserror::Error;
 
-use crate::messages::CommandMsg;
+use crate::messages::CommandRequest;
 
 /// SupersError enumerates all possible error types returned by supers.
 #[allow(clippy::enum_variant_names)]
@@ -16,6 +16,9 @@ pub enum SupersError {
     #[error("supers was unable to read configuration sources; details: {0}")]
     ApplicationConfigError(String),
 
+    #[error("supers failed to access the state file {0}; details: {1}")]
+    ApplicationStateFileError(String, String),
+
     #[error("supers was unable to start thread for program {0}; details: {1}")]
     ProgramThreadStartError(String, std::io::Error),
 
@@ -40,10 +43,29 @@ pub enum SupersError {
     )]
     ProgramProcessKillError(String, std::io::Error),
 
+    #[error(
+        "supers cannot apply the execution settings for program {0}; details: {1}"
+    )]
+    ProgramExecConfigError(String, String),
+
+    #[error(
+        "supers failed to build the environment for program {0}; details: {1}"
+    )]
+    ProgramEnvError(String, String),
+
+    #[error("hook {1} of program {0} failed; details: {2}")]
+    ProgramHookError(String, &'static str, String),
+
+    #[error("supers failed to set up the cgroup for program {0}; details: {1}")]
+    ProgramCgroupError(String, std::io::Error),
+
+    #[error("supers failed to watch the files of program {0}; details: {1}")]
+    ProgramWatchError(String, String),
+
     #[error("supers got error while sending a command message")]
     ProgramCommandChannelSendError {
         #[from]
-        source: SendError<CommandMsg>,
+        source: SendError<CommandRequest>,
     },
 
     #[error("supers got an io error")]
@@ -0,0 +1,342 @@
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::SystemTime,
+};
+
+use actix_web::{
+    body::{BodySize, MessageBody},
+    web::Bytes,
+};
+use crossbeam::channel::{bounded, Receiver, Sender, TrySendError};
+use serde::Serializer;
+use serde_derive::Serialize;
+use tracing::warn;
+
+use crate::{schedule::format_utc, state::ApplicationState};
+
+// Number of events kept in the history of the event bus.
+pub const EVENT_HISTORY: usize = 1000;
+// Number of events waiting for a subscriber to receive them.
+const SUBSCRIBER_BACKLOG: usize = 1000;
+
+/// Names of the kinds of events
+pub const EVENT_NAMES: [&str; 5] = [
+    "spawned",
+    "exited",
+    "restart_scheduled",
+    "stop_requested",
+    "fatal",
+];
+
+/// Lifecycle transitions of programs published on the event bus
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// A child was spawned with this pid
+    Spawned { pid: u32 },
+    /// The child exited; `code` is set if it exited by itself, and
+    /// `requested` if it was stopped by a command
+    Exited {
+        status: String,
+        code: Option<i32>,
+        requested: bool,
+    },
+    /// The restart policy restarts the program after `delay_ms`
+    RestartScheduled { delay_ms: u128 },
+    /// The child is being stopped by a command
+    StopRequested,
+    /// The thread running the program failed
+    Fatal { error: String },
+}
+
+impl EventKind {
+    /// Name of the event, as used in the `event` field of SSE.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Spawned { .. } => "spawned",
+            EventKind::Exited { .. } => "exited",
+            EventKind::RestartScheduled { .. } => "restart_scheduled",
+            EventKind::StopRequested => "stop_requested",
+            EventKind::Fatal { .. } => "fatal",
+        }
+    }
+}
+
+fn serialize_timestamp<S: Serializer>(
+    t: &SystemTime,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&format_utc(*t))
+}
+
+/// An event published on the event bus
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Event {
+    /// Sequence number of the event, starting at 1
+    pub id: u64,
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub timestamp: SystemTime,
+    pub program: String,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// Check that `names` are names of kinds of events.
+pub fn validate_names(names: &[String]) -> Result<(), String> {
+    match names.iter().find(|n| !EVENT_NAMES.contains(&n.as_str())) {
+        Some(name) => Err(format!("unknown event {name}")),
+        None => Ok(()),
+    }
+}
+
+impl Event {
+    /// Return `true` if the event is one of `events` of one of `programs`,
+    /// where empty lists match everything.
+    pub fn matches(&self, events: &[String], programs: &[String]) -> bool {
+        (events.is_empty() || events.iter().any(|e| e == self.kind.name()))
+            && (programs.is_empty() || programs.contains(&self.program))
+    }
+
+    /// Format the event as a message of a server-sent events stream.
+    pub fn to_sse(&self) -> String {
+        let data = serde_json::to_string(self).unwrap_or_default();
+        format!(
+            "id: {}\nevent: {}\ndata: {}\n\n",
+            self.id,
+            self.kind.name(),
+            data
+        )
+    }
+}
+
+/// Bounded history of the events of the application, with the wakers of the
+/// streams waiting for new events.
+#[derive(Debug)]
+pub struct EventBus {
+    next_id: u64,
+    capacity: usize,
+    history: VecDeque<Event>,
+    wakers: Vec<Waker>,
+    subscribers: Vec<Sender<Event>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::with_capacity(EVENT_HISTORY)
+    }
+}
+
+impl EventBus {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            next_id: 1,
+            capacity,
+            history: VecDeque::with_capacity(capacity),
+            wakers: vec![],
+            subscribers: vec![],
+        }
+    }
+
+    /// Record event `kind` of program `program` and wake up the streams.
+    pub fn publish(&mut self, program: &str, kind: EventKind) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        if self.history.len() == self.capacity {
+            self.history.pop_front();
+        }
+        let event = Event {
+            id,
+            timestamp: SystemTime::now(),
+            program: program.into(),
+            kind,
+        };
+        self.subscribers
+            .retain(|s| match s.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!(id, "subscriber is behind; dropping event");
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+        self.history.push_back(event);
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+        id
+    }
+
+    /// Receive the events published from now on. Events published while
+    /// `SUBSCRIBER_BACKLOG` events wait to be received are dropped.
+    pub fn subscribe(&mut self) -> Receiver<Event> {
+        let (tx, rx) = bounded(SUBSCRIBER_BACKLOG);
+        self.subscribers.push(tx);
+        rx
+    }
+
+    /// Id of the last event published, or 0 if there is none.
+    pub fn last_id(&self) -> u64 {
+        self.next_id - 1
+    }
+
+    /// Id of the oldest event in the history, if any.
+    pub fn oldest_id(&self) -> Option<u64> {
+        self.history.front().map(|e| e.id)
+    }
+
+    /// Events in the history published after event `id`.
+    pub fn since(&self, id: u64) -> impl Iterator<Item = &Event> {
+        self.history.iter().filter(move |e| e.id > id)
+    }
+
+    fn register(&mut self, waker: &Waker) {
+        if !self.wakers.iter().any(|w| w.will_wake(waker)) {
+            self.wakers.push(waker.clone());
+        }
+    }
+}
+
+/// Body of a server-sent events response streaming the events published
+/// after event `last_id`.
+pub struct EventStream {
+    app_state: Arc<Mutex<ApplicationState>>,
+    last_id: u64,
+}
+
+impl EventStream {
+    pub fn new(app_state: Arc<Mutex<ApplicationState>>, last_id: u64) -> Self {
+        Self { app_state, last_id }
+    }
+}
+
+impl MessageBody for EventStream {
+    type Error = Infallible;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        let mut a = this.app_state.lock().unwrap();
+        let mut chunk = String::new();
+        // Tell the client about the events that fell out of the history
+        // before it could receive them.
+        if let Some(oldest) =
+            a.events.oldest_id().filter(|id| *id > this.last_id + 1)
+        {
+            let data = serde_json::json!({
+                "first_missed": this.last_id + 1,
+                "last_missed": oldest - 1,
+            });
+            chunk.push_str(&format!("event: reset\ndata: {data}\n\n"));
+        }
+        chunk.extend(a.events.since(this.last_id).map(Event::to_sse));
+        if chunk.is_empty() {
+            a.events.register(cx.waker());
+            return Poll::Pending;
+        }
+        this.last_id = a.events.last_id();
+        Poll::Ready(Some(Ok(Bytes::from(chunk))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EventBus, EventKind, EventStream, SUBSCRIBER_BACKLOG};
+    use crate::state::ApplicationState;
+    use actix_web::body::MessageBody;
+    use anyhow::Result;
+    use std::{
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{Context, Poll, Waker},
+    };
+
+    #[test]
+    fn test_event_bus() {
+        let mut bus = EventBus::with_capacity(2);
+        assert_eq!(bus.last_id(), 0);
+        bus.publish("api", EventKind::Spawned { pid: 10 });
+        bus.publish("api", EventKind::StopRequested);
+        bus.publish("api", EventKind::Spawned { pid: 11 });
+        assert_eq!(bus.last_id(), 3);
+        // The oldest event fell out of the history
+        let ids: Vec<_> = bus.since(0).map(|e| e.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+        let ids: Vec<_> = bus.since(2).map(|e| e.id).collect();
+        assert_eq!(ids, vec![3]);
+        assert_eq!(bus.since(3).count(), 0);
+
+        let events = bus.subscribe();
+        bus.publish("api", EventKind::StopRequested);
+        assert_eq!(events.try_recv().map(|e| e.id), Ok(4));
+        // A subscriber that falls behind misses the newest events
+        for _ in 0..=SUBSCRIBER_BACKLOG {
+            bus.publish("api", EventKind::StopRequested);
+        }
+        assert_eq!(events.len(), SUBSCRIBER_BACKLOG);
+        assert_eq!(events.try_iter().last().map(|e| e.id), Some(1004));
+        drop(events);
+        bus.publish("api", EventKind::StopRequested);
+        assert!(bus.subscribers.is_empty());
+    }
+
+    #[test]
+    fn test_event_stream() -> Result<()> {
+        let app_state = Arc::new(Mutex::new(ApplicationState::default()));
+        let mut stream = EventStream::new(app_state.clone(), 0);
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending());
+        app_state.lock().unwrap().events.publish(
+            "api",
+            EventKind::Exited {
+                status: "exit status: 1".into(),
+                code: Some(1),
+                requested: false,
+            },
+        );
+        let Poll::Ready(Some(Ok(chunk))) =
+            Pin::new(&mut stream).poll_next(&mut cx)
+        else {
+            panic!("expected an event");
+        };
+        let chunk = String::from_utf8(chunk.to_vec())?;
+        assert!(chunk.starts_with("id: 1\nevent: exited\ndata: {"));
+        assert!(chunk.contains(r#""program":"api","event":"exited","#));
+        assert!(chunk.contains(r#""code":1"#));
+        assert!(chunk.ends_with("}\n\n"));
+        assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending());
+
+        // A client resuming after events that left the history is told so
+        let app_state = Arc::new(Mutex::new(ApplicationState {
+            events: EventBus::with_capacity(2),
+            ..Default::default()
+        }));
+        for _ in 0..4 {
+            let mut a = app_state.lock().unwrap();
+            a.events.publish("api", EventKind::StopRequested);
+        }
+        let mut stream = EventStream::new(app_state.clone(), 1);
+        let Poll::Ready(Some(Ok(chunk))) =
+            Pin::new(&mut stream).poll_next(&mut cx)
+        else {
+            panic!("expected a reset");
+        };
+        let chunk = String::from_utf8(chunk.to_vec())?;
+        assert!(chunk.starts_with(
+            "event: reset\ndata: {\"first_missed\":2,\"last_missed\":2}\n\n"
+        ));
+        assert!(chunk.contains("id: 3\n"));
+        assert!(chunk.contains("id: 4\n"));
+        assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending());
+        Ok(())
+    }
+}
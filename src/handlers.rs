@@ -1,8 +1,36 @@
-use actix_web::{get, post, web, HttpResponse, Responder};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    get, post, rt::time::sleep, web, HttpRequest, HttpResponse, Responder,
+};
+use crossbeam::channel::RecvTimeoutError;
+use serde_derive::Deserialize;
+use tracing::error;
 
 use crate::WebAppState;
 
-use crate::messages::CommandMsg;
+use crate::config::{parse_label_selector, StdinMode};
+use crate::events::EventStream;
+use crate::messages::{CommandMsg, CommandReply, CommandRequest};
+use crate::persist;
+use crate::process::{self, parse_signal};
+use crate::schedule::format_utc;
+use crate::state::{ProgramStatus, RunRecord};
+
+// Default time to wait for the replies of the programs to a command.
+const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+// Interval between checks of the status of programs.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Default time a restarted program has to come back during a rolling restart.
+const DEFAULT_ROLLING_RESTART_TIMEOUT: Duration = Duration::from_secs(30);
+// Default time a restarted program must keep running to count as back.
+const DEFAULT_ROLLING_RESTART_SETTLE: Duration = Duration::from_secs(1);
+// Time for the reply to a handover to reach the client before supers
+// re-executes itself.
+const HANDOVER_DELAY: Duration = Duration::from_millis(200);
 
 /// Web routes
 
@@ -19,18 +47,288 @@ pub async fn get_app_status(data: web::Data<WebAppState>) -> impl Responder {
     HttpResponse::Ok().body(body)
 }
 
+#[post("/app/handover")]
+pub async fn handover(data: web::Data<WebAppState>) -> impl Responder {
+    let Some(state_dir) = data.state_dir.clone() else {
+        let body = "Handover requires a state_dir.\n";
+        return HttpResponse::BadRequest().body(body);
+    };
+    let mut targets: Vec<String> = data.channels.keys().cloned().collect();
+    targets.sort();
+    let params = CommandParams::default();
+    let response =
+        send_to_programs(&data, &targets, CommandMsg::Handover, &params).await;
+    if !response.status().is_success() {
+        return response;
+    }
+    if let Err(e) = persist::save(&state_dir, &data.app_state, &mut vec![]) {
+        return HttpResponse::InternalServerError().body(format!("{}\n", e));
+    }
+    thread::spawn(|| {
+        thread::sleep(HANDOVER_DELAY);
+        let e = process::reexec();
+        error!(error = ?e, "cannot re-execute supers");
+    });
+    response
+}
+
+#[derive(Deserialize)]
+pub struct ProgramsQuery {
+    /// Label selector, such as `tier=web,env=prod`
+    label: Option<String>,
+}
+
 #[get("/programs")]
-pub async fn get_programs(data: web::Data<WebAppState>) -> impl Responder {
+pub async fn get_programs(
+    data: web::Data<WebAppState>,
+    query: web::Query<ProgramsQuery>,
+) -> impl Responder {
+    let selector = match query.label.as_deref().map(parse_label_selector) {
+        Some(None) => {
+            let body = "Invalid label selector; expected key=value[,...]\n";
+            return HttpResponse::BadRequest().body(body);
+        }
+        Some(Some(selector)) => selector,
+        None => vec![],
+    };
+    let selected = |name: &String| {
+        let labels = data.programs.get(name).map(|p| &p.labels);
+        selector
+            .iter()
+            .all(|(k, v)| labels.and_then(|l| l.get(k)) == Some(v))
+    };
     let d = data.app_state.lock().unwrap();
     let mut body = String::from("Program Statuses:\n");
-    for (key, val) in &d.programs {
-        let s = format!("{}: {}\n", key, val);
+    for (key, val) in d.programs.iter().filter(|(k, _)| selected(k)) {
+        let s = format!("{}: {}\n", key, val.status);
         body.push_str(&s);
     }
 
     HttpResponse::Ok().body(body)
 }
 
+// Names of the programs targeted by `name`: the program itself, or all the
+// replicas of a program with `replicas` set.
+fn targets(data: &WebAppState, name: &str) -> Option<Vec<String>> {
+    if data.channels.contains_key(name) {
+        Some(vec![name.to_string()])
+    } else {
+        data.replicas.get(name).cloned()
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct CommandParams {
+    /// Wait until the programs reach the target status of the command
+    wait: Option<bool>,
+    /// Time to wait for the replies of the programs and, with `wait`, for
+    /// them to reach the target status (e.g., "500ms", "5s" or "1m")
+    timeout: Option<String>,
+}
+
+// Parse a duration such as "500ms", "5s" or "1m"; plain numbers are seconds.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let value: u64 = value.parse().ok()?;
+    match unit {
+        "ms" => Some(Duration::from_millis(value)),
+        "s" => Some(Duration::from_secs(value)),
+        "m" => Some(Duration::from_secs(value * 60)),
+        _ => None,
+    }
+}
+
+// The duration in the query parameter `param`, with `value`, or `default` if
+// it is not set. Return the error message if it is invalid.
+fn duration_param(
+    param: &str,
+    value: &Option<String>,
+    default: Duration,
+) -> Result<Duration, String> {
+    match value.as_deref() {
+        Some(v) => parse_duration(v)
+            .ok_or_else(|| format!("Invalid {} {}.\n", param, v)),
+        None => Ok(default),
+    }
+}
+
+// Return `true` if a program with `status` reached the target of `msg`.
+fn reached_target(msg: &CommandMsg, status: ProgramStatus) -> bool {
+    match msg {
+        CommandMsg::Start | CommandMsg::Restart => {
+            matches!(status, ProgramStatus::Running | ProgramStatus::Completed)
+        }
+        CommandMsg::Stop => status != ProgramStatus::Running,
+        CommandMsg::Signal(_)
+        | CommandMsg::SignalAll(_)
+        | CommandMsg::Stdin(_)
+        | CommandMsg::Handover => true,
+    }
+}
+
+// Describe the outcome, `reply`, of a command to program `name`.
+fn describe(name: &str, reply: &CommandReply) -> String {
+    match reply {
+        CommandReply::Spawned(pid) => {
+            format!("Program {} is running with pid {}.\n", name, pid)
+        }
+        CommandReply::AlreadyRunning => {
+            format!("Program {} is already running.\n", name)
+        }
+        CommandReply::Stopped => {
+            format!("Program {} has been stopped.\n", name)
+        }
+        CommandReply::NotRunning => {
+            format!("Program {} is not running.\n", name)
+        }
+        CommandReply::Delivered => {
+            format!("Program {} has been sent the command.\n", name)
+        }
+        CommandReply::WaitingForDependencies => {
+            format!("Program {} is waiting for its dependencies.\n", name)
+        }
+        CommandReply::SpawnError(e) => {
+            format!("Program {} failed to start: {}\n", name, e)
+        }
+        CommandReply::Rejected(e) => {
+            format!("Program {} rejected the input: {}\n", name, e)
+        }
+        CommandReply::HandedOver(pid) => {
+            format!("Program {} hands over its child with pid {}.\n", name, pid)
+        }
+    }
+}
+
+// Wait until program `name` reaches the target status of `msg`, until
+// `deadline`. Return `true` if it does.
+async fn wait_for_target(
+    data: &WebAppState,
+    name: &str,
+    msg: &CommandMsg,
+    deadline: Instant,
+) -> bool {
+    loop {
+        let status = {
+            let d = data.app_state.lock().unwrap();
+            d.programs.get(name).map(|s| s.status).unwrap_or_default()
+        };
+        if reached_target(msg, status) {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+// Send the command `msg` to the programs targeted by `name`.
+async fn send_command(
+    data: &WebAppState,
+    name: &str,
+    msg: CommandMsg,
+    params: &CommandParams,
+) -> HttpResponse {
+    let Some(targets) = targets(data, name) else {
+        let body = format!("No program with name {} found.\n", &name);
+        return HttpResponse::NotFound().body(body);
+    };
+    send_to_programs(data, &targets, msg, params).await
+}
+
+// Send the command `msg` to the programs of group `group`.
+async fn send_group_command(
+    data: &WebAppState,
+    group: &str,
+    msg: CommandMsg,
+    params: &CommandParams,
+) -> HttpResponse {
+    let Some(members) = data.groups.get(group) else {
+        let body = format!("No group with name {} found.\n", &group);
+        return HttpResponse::NotFound().body(body);
+    };
+    send_to_programs(data, members, msg, params).await
+}
+
+// Send the command `msg` to each program in `targets` and report the replies
+// of the programs, waiting for them to reach the target status if requested.
+async fn send_to_programs(
+    data: &WebAppState,
+    targets: &[String],
+    msg: CommandMsg,
+    params: &CommandParams,
+) -> HttpResponse {
+    let timeout = match duration_param(
+        "timeout",
+        &params.timeout,
+        DEFAULT_COMMAND_TIMEOUT,
+    ) {
+        Ok(timeout) => timeout,
+        Err(body) => return HttpResponse::BadRequest().body(body),
+    };
+    let deadline = Instant::now() + timeout;
+    let mut replies = vec![];
+    for target in targets {
+        // get the channel associated with this program and send it the message
+        let tx = data.channels.get(target).unwrap();
+        let (request, reply_rx) = CommandRequest::with_reply(msg.clone());
+        if tx.send(request).is_err() {
+            let body = format!("Error sending message to {} channel\n", target);
+            return HttpResponse::BadRequest().body(body);
+        }
+        replies.push((target, reply_rx));
+    }
+    let mut body = String::new();
+    let (mut failed, mut rejected, mut timed_out) = (false, false, false);
+    for (target, reply_rx) in replies {
+        let wait = deadline.saturating_duration_since(Instant::now());
+        match web::block(move || reply_rx.recv_timeout(wait)).await {
+            Ok(Ok(reply)) => {
+                failed |= matches!(reply, CommandReply::SpawnError(_));
+                rejected |= matches!(reply, CommandReply::Rejected(_));
+                body.push_str(&describe(target, &reply));
+            }
+            Ok(Err(RecvTimeoutError::Disconnected)) => {
+                failed = true;
+                body.push_str(&format!(
+                    "Program {} failed while handling the command.\n",
+                    target
+                ));
+            }
+            _ => {
+                timed_out = true;
+                body.push_str(&format!(
+                    "Program {} did not reply within {:?}.\n",
+                    target, timeout
+                ));
+            }
+        }
+    }
+    if params.wait == Some(true) && !failed && !rejected && !timed_out {
+        for target in targets {
+            if !wait_for_target(data, target, &msg, deadline).await {
+                timed_out = true;
+                body.push_str(&format!(
+                    "Program {} did not reach the target status within {:?}.\n",
+                    target, timeout
+                ));
+            }
+        }
+    }
+    if failed {
+        HttpResponse::InternalServerError().body(body)
+    } else if rejected {
+        HttpResponse::ServiceUnavailable().body(body)
+    } else if timed_out {
+        HttpResponse::GatewayTimeout().body(body)
+    } else {
+        HttpResponse::Ok().body(body)
+    }
+}
+
 #[get("/programs/{name}")]
 pub async fn get_program(
     data: web::Data<WebAppState>,
@@ -38,84 +336,497 @@ pub async fn get_program(
 ) -> impl Responder {
     let name = &path.0;
     let d = data.app_state.lock().unwrap();
-    if !d.programs.contains_key(name) {
+    let Some(targets) = targets(&data, name)
+        .filter(|t| t.iter().all(|t| d.programs.contains_key(t)))
+    else {
         let body = format!("No program with name {} found.\n", &name);
         return HttpResponse::NotFound().body(body);
+    };
+    let mut body = String::new();
+    for name in &targets {
+        let state = d.programs.get(name).unwrap();
+        body.push_str(&format!(
+            "Status of program {} is: {}\n",
+            name, state.status
+        ));
+        if let Some(error) = &state.fatal_error {
+            body.push_str(&format!(
+                "Fatal error of program {}: {}\n",
+                name, error
+            ));
+        }
+        if let Some(reason) = &state.last_exit {
+            body.push_str(&format!(
+                "Last exit of program {}: {}\n",
+                name, reason
+            ));
+        }
+        if let Some(code) = state.exit_code {
+            body.push_str(&format!(
+                "Exit code of program {}: {}\n",
+                name, code
+            ));
+        }
+        if let Some(next_run) = state.next_run {
+            let next_run = format_utc(next_run);
+            body.push_str(&format!(
+                "Next run of program {}: {}\n",
+                name, next_run
+            ));
+        }
     }
-    let status = d.programs.get(name).unwrap();
-    let body = format!("Status of program {} is: {}\n", name, &status);
     HttpResponse::Ok().body(body)
 }
 
-#[post("/programs/{name}/start")]
-pub async fn start_program(
+// Describe `run` in one line.
+fn describe_run(run: &RunRecord) -> String {
+    let mut line =
+        format!("pid {}, started {}", run.pid, format_utc(run.started));
+    let Some(ended) = run.ended else {
+        return match run.reason {
+            Some(reason) => format!("{line}, {reason}\n"),
+            None => line + ", running\n",
+        };
+    };
+    line.push_str(&format!(", ended {}", format_utc(ended)));
+    match (run.exit_code, run.signal) {
+        (Some(code), _) => line.push_str(&format!(", exit code {}", code)),
+        (None, Some(signal)) => line.push_str(&format!(", signal {}", signal)),
+        (None, None) => {}
+    }
+    if let Some(reason) = run.reason {
+        line.push_str(&format!(", {}", reason));
+    }
+    line + "\n"
+}
+
+#[get("/programs/{name}/history")]
+pub async fn get_program_history(
     data: web::Data<WebAppState>,
     path: web::Path<(String,)>,
 ) -> impl Responder {
     let name = &path.0;
-    let d = data.app_state.lock().unwrap();
-    // check that `name` is an existing program
-    if !d.programs.contains_key(name) {
+    let Some(targets) = targets(&data, name) else {
         let body = format!("No program with name {} found.\n", &name);
         return HttpResponse::NotFound().body(body);
+    };
+    let d = data.app_state.lock().unwrap();
+    let mut body = String::new();
+    for name in &targets {
+        body.push_str(&format!("History of program {}:\n", name));
+        let history = d.programs.get(name).map(|s| &s.history);
+        for run in history.into_iter().flatten() {
+            body.push_str(&describe_run(run));
+        }
     }
+    HttpResponse::Ok().body(body)
+}
 
-    // get the channel associated with this program and send it a start message
-    let tx = data.channels.get(name).unwrap();
-    if let Ok(_r) = tx.send(CommandMsg::Start) {
-        let body = format!("Program {} has been instructed to start.\n", name);
-        HttpResponse::Ok().body(body)
-    } else {
-        let body = format!("Error sending message to {} channel\n", name);
-        HttpResponse::BadRequest().body(body)
-    }
+#[post("/programs/{name}/start")]
+pub async fn start_program(
+    data: web::Data<WebAppState>,
+    path: web::Path<(String,)>,
+    params: web::Query<CommandParams>,
+) -> impl Responder {
+    send_command(&data, &path.0, CommandMsg::Start, &params).await
 }
 
 #[post("/programs/{name}/stop")]
 pub async fn stop_program(
     data: web::Data<WebAppState>,
     path: web::Path<(String,)>,
+    params: web::Query<CommandParams>,
+) -> impl Responder {
+    send_command(&data, &path.0, CommandMsg::Stop, &params).await
+}
+
+#[post("/programs/{name}/restart")]
+pub async fn restart_program(
+    data: web::Data<WebAppState>,
+    path: web::Path<(String,)>,
+    params: web::Query<CommandParams>,
+) -> impl Responder {
+    send_command(&data, &path.0, CommandMsg::Restart, &params).await
+}
+
+#[post("/programs/{name}/signal/{signal}")]
+pub async fn signal_program(
+    data: web::Data<WebAppState>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (name, signal_name) = path.into_inner();
+    let Some(signal) = parse_signal(&signal_name) else {
+        let body = format!("Unknown signal {}.\n", signal_name);
+        return HttpResponse::BadRequest().body(body);
+    };
+    let Some(targets) = targets(&data, &name) else {
+        let body = format!("No program with name {} found.\n", &name);
+        return HttpResponse::NotFound().body(body);
+    };
+    if let Some(denied) = targets
+        .iter()
+        .find(|t| !data.programs[*t].signal_allowed(signal))
+    {
+        let body = format!(
+            "Signal {} is not allowed for program {}.\n",
+            signal_name, denied
+        );
+        return HttpResponse::Forbidden().body(body);
+    }
+    let msg = CommandMsg::Signal(signal);
+    send_to_programs(&data, &targets, msg, &Default::default()).await
+}
+
+#[post("/programs/{name}/stdin")]
+pub async fn write_program_stdin(
+    data: web::Data<WebAppState>,
+    path: web::Path<(String,)>,
+    body: web::Bytes,
 ) -> impl Responder {
     let name = &path.0;
-    let d = data.app_state.lock().unwrap();
-    // check that `name` is an existing program
-    if !d.programs.contains_key(name) {
+    let Some(targets) = targets(&data, name) else {
         let body = format!("No program with name {} found.\n", &name);
         return HttpResponse::NotFound().body(body);
+    };
+    if let Some(t) = targets
+        .iter()
+        .find(|t| data.programs[*t].stdin != StdinMode::Pipe)
+    {
+        let body = format!("Program {} does not have stdin = \"pipe\".\n", t);
+        return HttpResponse::BadRequest().body(body);
     }
+    let msg = CommandMsg::Stdin(body.to_vec());
+    send_to_programs(&data, &targets, msg, &Default::default()).await
+}
 
-    // get the channel associated with this program and send it a stop message
-    let tx = data.channels.get(name).unwrap();
-    if let Ok(_r) = tx.send(CommandMsg::Stop) {
-        let body = format!("Program {} has been instructed to stop.\n", name);
-        HttpResponse::Ok().body(body)
-    } else {
-        let body = format!("Error sending message to {} channel\n", name);
-        HttpResponse::BadRequest().body(body)
+#[post("/groups/{group}/start")]
+pub async fn start_group(
+    data: web::Data<WebAppState>,
+    path: web::Path<(String,)>,
+    params: web::Query<CommandParams>,
+) -> impl Responder {
+    send_group_command(&data, &path.0, CommandMsg::Start, &params).await
+}
+
+#[post("/groups/{group}/stop")]
+pub async fn stop_group(
+    data: web::Data<WebAppState>,
+    path: web::Path<(String,)>,
+    params: web::Query<CommandParams>,
+) -> impl Responder {
+    send_group_command(&data, &path.0, CommandMsg::Stop, &params).await
+}
+
+#[post("/groups/{group}/restart")]
+pub async fn restart_group(
+    data: web::Data<WebAppState>,
+    path: web::Path<(String,)>,
+    params: web::Query<CommandParams>,
+) -> impl Responder {
+    send_group_command(&data, &path.0, CommandMsg::Restart, &params).await
+}
+
+#[derive(Deserialize)]
+pub struct RollingRestartParams {
+    /// Number of programs restarted at the same time
+    max_unavailable: Option<usize>,
+    /// Time each restarted program has to come back (e.g., "30s" or "1m")
+    timeout: Option<String>,
+    /// Time each restarted program must keep running to count as back
+    settle: Option<String>,
+}
+
+// Wait until program `name`, restarted when it had `starts` starts, runs a
+// new child for `settle`, without restarting it in between, and within
+// `timeout`. Return the reason if it does not.
+async fn wait_until_back(
+    data: &WebAppState,
+    name: &str,
+    starts: u64,
+    timeout: Duration,
+    settle: Duration,
+) -> Result<(), String> {
+    let deadline = Instant::now() + timeout;
+    // The starts of the program when it was first seen running, and since
+    // when it runs.
+    let mut running_since: Option<(u64, Instant)> = None;
+    loop {
+        let (status, current) = {
+            let d = data.app_state.lock().unwrap();
+            d.programs
+                .get(name)
+                .map_or((ProgramStatus::Stopped, starts), |state| {
+                    (state.status, state.starts)
+                })
+        };
+        match (status, current > starts) {
+            // The program will not come back by itself, even if the restart
+            // was not processed yet.
+            (ProgramStatus::Fatal | ProgramStatus::Failed, _) => {
+                return Err(format!("status is {}", status))
+            }
+            (ProgramStatus::Running, true) => {
+                // A child that crashed and was restarted in between has to
+                // settle again.
+                let since = match running_since {
+                    Some((seen, since)) if seen == current => since,
+                    _ => {
+                        running_since = Some((current, Instant::now()));
+                        Instant::now()
+                    }
+                };
+                if since.elapsed() >= settle {
+                    return Ok(());
+                }
+            }
+            (ProgramStatus::Running, false) => {}
+            (status, true) => return Err(format!("status is {}", status)),
+            (_, false) => running_since = None,
+        }
+        if Instant::now() >= deadline {
+            return Err("timed out".into());
+        }
+        sleep(POLL_INTERVAL).await;
     }
 }
 
-#[post("/programs/{name}/restart")]
-pub async fn restart_program(
+#[post("/groups/{group}/rolling-restart")]
+pub async fn rolling_restart_group(
     data: web::Data<WebAppState>,
     path: web::Path<(String,)>,
+    params: web::Query<RollingRestartParams>,
 ) -> impl Responder {
-    let name = &path.0;
-    let d = data.app_state.lock().unwrap();
-    // check that `name` is an existing program
-    if !d.programs.contains_key(name) {
-        let body = format!("No program with name {} found.\n", &name);
+    let group = &path.0;
+    let Some(members) = data.groups.get(group) else {
+        let body = format!("No group with name {} found.\n", &group);
         return HttpResponse::NotFound().body(body);
+    };
+    let max_unavailable = params.max_unavailable.unwrap_or(1).max(1);
+    let timeout = match duration_param(
+        "timeout",
+        &params.timeout,
+        DEFAULT_ROLLING_RESTART_TIMEOUT,
+    ) {
+        Ok(timeout) => timeout,
+        Err(body) => return HttpResponse::BadRequest().body(body),
+    };
+    let settle = match duration_param(
+        "settle",
+        &params.settle,
+        DEFAULT_ROLLING_RESTART_SETTLE,
+    ) {
+        Ok(settle) => settle,
+        Err(body) => return HttpResponse::BadRequest().body(body),
+    };
+    let mut body = String::new();
+    for batch in members.chunks(max_unavailable) {
+        let mut restarted = vec![];
+        for name in batch {
+            let (status, starts) = {
+                let d = data.app_state.lock().unwrap();
+                d.programs
+                    .get(name)
+                    .map_or((ProgramStatus::Stopped, 0), |s| {
+                        (s.status, s.starts)
+                    })
+            };
+            // Restarting would start a program that is not meant to run.
+            if status != ProgramStatus::Running {
+                body.push_str(&format!(
+                    "Program {} is {}, skipped.\n",
+                    name, status
+                ));
+                continue;
+            }
+            let tx = data.channels.get(name).unwrap();
+            if tx.send(CommandMsg::Restart.into()).is_err() {
+                body.push_str(&format!(
+                    "Error sending message to {} channel\n",
+                    name
+                ));
+                return HttpResponse::BadRequest().body(body);
+            }
+            restarted.push((name, starts));
+        }
+        for (name, starts) in restarted {
+            if let Err(reason) =
+                wait_until_back(&data, name, starts, timeout, settle).await
+            {
+                body.push_str(&format!(
+                    "Rolling restart of group {} aborted: program {} did not come back ({}).\n",
+                    group, name, reason
+                ));
+                return HttpResponse::InternalServerError().body(body);
+            }
+            body.push_str(&format!("Program {} has been restarted.\n", name));
+        }
     }
+    HttpResponse::Ok().body(body)
+}
 
-    // get the channel associated with this program and send it a restart message
-    let tx = data.channels.get(name).unwrap();
-    if let Ok(_r) = tx.send(CommandMsg::Restart) {
-        let body =
-            format!("Program {} has been instructed to restart.\n", name);
-        HttpResponse::Ok().body(body)
-    } else {
-        let body = format!("Error sending message to {} channel\n", name);
-        HttpResponse::BadRequest().body(body)
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    /// Replay the events in the history published after this event
+    since: Option<u64>,
+}
+
+#[get("/events")]
+pub async fn get_events(
+    data: web::Data<WebAppState>,
+    query: web::Query<EventsQuery>,
+    req: HttpRequest,
+) -> impl Responder {
+    // Reconnecting clients resume from the last event they received.
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+    let since = match query.since.or(last_event_id) {
+        Some(since) => since,
+        None => data.app_state.lock().unwrap().events.last_id(),
+    };
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .body(EventStream::new(data.app_state.clone(), since))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use actix_web::{body::to_bytes, http::StatusCode, test, web::Data, App};
+    use anyhow::Result;
+
+    use super::{
+        restart_program, rolling_restart_group, start_program, wait_until_back,
+    };
+    use crate::config::ProgramConfig;
+    use crate::messages::{CommandMsg, CommandRequest};
+    use crate::programs::start_program_threads;
+    use crate::state::{ApplicationState, ProgramStatus};
+    use crate::WebAppState;
+
+    // Start the threads of `programs`, all members of group `group`.
+    fn start_programs(
+        programs: Vec<ProgramConfig>,
+        group: &str,
+    ) -> Result<WebAppState> {
+        let app_state = Arc::new(Mutex::new(ApplicationState::default()));
+        let names = programs.iter().map(|p| p.name.clone()).collect();
+        let configs = programs.iter().map(|p| (p.name.clone(), p.clone()));
+        let (_threads, channels) =
+            start_program_threads(programs.clone(), &app_state)?;
+        Ok(WebAppState {
+            app_state,
+            channels,
+            replicas: HashMap::new(),
+            groups: HashMap::from([(group.to_string(), names)]),
+            programs: configs.collect(),
+            state_dir: None,
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_restart_after_spawn_error() -> Result<()> {
+        let p = ProgramConfig {
+            name: "typo".into(),
+            cmd: "/nonexistent/program".into(),
+            ..Default::default()
+        };
+        let data = start_programs(vec![p], "web")?;
+        let app_state = data.app_state.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(data))
+                .service(start_program)
+                .service(restart_program),
+        )
+        .await;
+        // The failed spawn leaves the program `Fatal` without a child, and
+        // each restart tries to spawn it again.
+        for command in ["start", "restart", "restart"] {
+            let req = test::TestRequest::post()
+                .uri(&format!("/programs/typo/{}", command))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            let body = to_bytes(resp.into_body()).await.unwrap();
+            assert!(String::from_utf8(body.to_vec())?
+                .starts_with("Program typo failed to start"));
+            assert_eq!(
+                app_state.lock().unwrap().programs["typo"].status,
+                ProgramStatus::Fatal
+            );
+        }
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_rolling_restart_skips_stopped_programs() -> Result<()> {
+        let program = |name: &str| ProgramConfig {
+            name: name.into(),
+            cmd: "sleep".into(),
+            args: vec!["30".into()],
+            ..Default::default()
+        };
+        let data = start_programs(vec![program("a"), program("b")], "web")?;
+        let (request, reply) = CommandRequest::with_reply(CommandMsg::Start);
+        data.channels["a"].send(request)?;
+        reply.recv()?;
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(data.clone()))
+                .service(rolling_restart_group),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/groups/web/rolling-restart?settle=100ms&timeout=bad")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::post()
+            .uri("/groups/web/rolling-restart?settle=100ms&timeout=5s")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(
+            String::from_utf8(body.to_vec())?,
+            "Program a has been restarted.\nProgram b is Stopped, skipped.\n"
+        );
+        // The skipped program was never started
+        assert!(!data.app_state.lock().unwrap().programs.contains_key("b"));
+        let (request, reply) = CommandRequest::with_reply(CommandMsg::Stop);
+        data.channels["a"].send(request)?;
+        reply.recv()?;
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_wait_until_back_aborts_on_fatal() -> Result<()> {
+        let data = start_programs(vec![], "web")?;
+        data.app_state
+            .lock()
+            .unwrap()
+            .programs
+            .entry("typo".into())
+            .or_default()
+            .status = ProgramStatus::Fatal;
+        // The restart was not processed yet, but the program is `Fatal`.
+        let timeout = Duration::from_secs(30);
+        let started = Instant::now();
+        let result =
+            wait_until_back(&data, "typo", 0, timeout, Duration::ZERO).await;
+        assert_eq!(result, Err("status is Fatal".into()));
+        assert!(started.elapsed() < timeout);
+        Ok(())
     }
 }
@@ -0,0 +1,88 @@
+use std::{
+    os::unix::process::CommandExt,
+    thread,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, instrument};
+
+use crate::{
+    config::{HookConfig, ProgramConfig},
+    errors::SupersError,
+    init, process,
+    programs::build_command,
+};
+
+// Amount of time between checks for the completion of a hook.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Run the hook `name` of program `p`, if configured, and wait for it to
+/// finish. The hook runs in its own process group, which is killed if the
+/// hook does not finish within its timeout.
+///
+/// Return an error if the hook cannot be spawned, exits with a non-success
+/// status, or times out.
+#[instrument(level = "debug", skip(p, hook), fields(program = p.name))]
+pub fn run_hook(
+    p: &ProgramConfig,
+    name: &'static str,
+    hook: &Option<HookConfig>,
+) -> Result<(), SupersError> {
+    let Some(hook) = hook else {
+        return Ok(());
+    };
+    let err =
+        |msg: String| SupersError::ProgramHookError(p.name.clone(), name, msg);
+    debug!(cmd = hook.cmd, args = ?hook.args, "running hook");
+    let mut cmd = build_command(p, &hook.cmd, &hook.args)?;
+    cmd.process_group(0);
+    let mut child = init::spawn(&mut cmd).map_err(|e| err(e.to_string()))?;
+    let deadline = Instant::now() + Duration::from_secs(hook.timeout_secs);
+    let result = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                debug!(%status, "hook finished");
+                break status
+                    .success()
+                    .then_some(())
+                    .ok_or_else(|| err(format!("exited with {status}")));
+            }
+            Ok(None) => {}
+            Err(e) => break Err(err(e.to_string())),
+        }
+        if Instant::now() >= deadline {
+            let _ = process::kill_process_group(child.id());
+            let _ = child.wait();
+            break Err(err(format!(
+                "timed out after {} seconds",
+                hook.timeout_secs
+            )));
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    init::release(child.id());
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::run_hook;
+    use crate::config::{HookConfig, ProgramConfig};
+
+    fn hook(script: &str, timeout_secs: u64) -> Option<HookConfig> {
+        Some(HookConfig {
+            cmd: "/bin/sh".into(),
+            args: vec!["-c".into(), script.into()],
+            timeout_secs,
+        })
+    }
+
+    #[test]
+    fn test_run_hook() {
+        let p = ProgramConfig::default();
+        assert!(run_hook(&p, "pre_start", &None).is_ok());
+        assert!(run_hook(&p, "pre_start", &hook("true", 5)).is_ok());
+        assert!(run_hook(&p, "pre_start", &hook("exit 3", 5)).is_err());
+        assert!(run_hook(&p, "pre_start", &hook("sleep 5", 0)).is_err());
+    }
+}
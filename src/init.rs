@@ -0,0 +1,384 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs, io, mem,
+    path::PathBuf,
+    process::{self, Child, Command},
+    ptr,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::Sender;
+use tracing::{debug, info, instrument, warn};
+
+use crate::{
+    errors::SupersError,
+    messages::{CommandMsg, CommandRequest},
+    persist,
+    state::{ApplicationState, ApplicationStatus, EndReason, ProgramStatus},
+};
+
+// Signals forwarded to the children in init mode.
+const FORWARDED_SIGNALS: [i32; 7] = [
+    libc::SIGHUP,
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTERM,
+    libc::SIGUSR1,
+    libc::SIGUSR2,
+    libc::SIGWINCH,
+];
+// Signals that stop the application, besides being forwarded.
+const STOP_SIGNALS: [i32; 3] = [libc::SIGINT, libc::SIGQUIT, libc::SIGTERM];
+// Amount of time the init thread waits for a signal before checking whether
+// the application stopped.
+const TICK: Duration = Duration::from_millis(100);
+// Interval between scans for orphans that no signal announced.
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+// Children that supers waits for itself, which the reaper leaves alone.
+static HELD: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+
+// Children of supers
+// ==================
+
+/// Spawn `cmd`, hiding the child from the reaper until `release` is called
+/// with its pid.
+pub fn spawn(cmd: &mut Command) -> io::Result<Child> {
+    // The lock keeps the reaper from seeing the child before it is held.
+    let mut held = HELD.lock().unwrap();
+    let child = cmd.spawn()?;
+    held.insert(child.id());
+    Ok(child)
+}
+
+/// Hide child `pid`, spawned by the instance of supers that handed it over,
+/// from the reaper until `release` is called with its pid.
+pub fn hold(pid: u32) {
+    HELD.lock().unwrap().insert(pid);
+}
+
+/// Let the reaper reap child `pid`, once its exit status was collected or
+/// nobody waits for it anymore.
+pub fn release(pid: u32) {
+    HELD.lock().unwrap().remove(&pid);
+}
+
+// Pids of the zombie children of supers.
+fn zombie_children() -> Vec<u32> {
+    let me = process::id();
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return vec![];
+    };
+    entries
+        .flatten()
+        .filter_map(|e| e.file_name().to_str()?.parse::<u32>().ok())
+        .filter(|pid| {
+            // The state and the parent follow the name, in parentheses.
+            fs::read_to_string(format!("/proc/{pid}/stat"))
+                .ok()
+                .and_then(|stat| {
+                    let (_, fields) = stat.rsplit_once(')')?;
+                    let mut fields = fields.split_whitespace();
+                    Some((fields.next()? == "Z", fields.next()?.parse().ok()?))
+                })
+                .is_some_and(|(zombie, ppid): (bool, u32)| zombie && ppid == me)
+        })
+        .collect()
+}
+
+// Reap the zombie children of supers that nobody waits for, such as the
+// orphaned descendants of the programs.
+fn reap_orphans() {
+    reap(zombie_children());
+}
+
+// Reap the children `pids` that are not held.
+fn reap(pids: Vec<u32>) {
+    let held = HELD.lock().unwrap();
+    for pid in pids.into_iter().filter(|p| !held.contains(p)) {
+        let mut status = 0;
+        let pid = pid as libc::pid_t;
+        if unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } > 0 {
+            debug!(pid, status, "reaped orphan");
+        }
+    }
+}
+
+// Init mode
+// =========
+
+/// Make supers the reaper of its orphaned descendants.
+pub fn set_subreaper() -> io::Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn signal_set() -> libc::sigset_t {
+    unsafe {
+        let mut set = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGCHLD);
+        for signal in FORWARDED_SIGNALS {
+            libc::sigaddset(&mut set, signal);
+        }
+        set
+    }
+}
+
+/// Block the signals handled by the init thread in the calling thread and in
+/// the threads it starts from now on. Children start with no signal blocked,
+/// since `process::configure_command` unblocks them right before `exec`.
+pub fn block_signals() -> io::Result<()> {
+    let set = signal_set();
+    let result = unsafe {
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut())
+    };
+    if result != 0 {
+        return Err(io::Error::from_raw_os_error(result));
+    }
+    Ok(())
+}
+
+// Wait up to `timeout` for one of the signals of `set`.
+fn wait_signal(set: &libc::sigset_t, timeout: Duration) -> Option<i32> {
+    let timeout = libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    };
+    let signal = unsafe { libc::sigtimedwait(set, ptr::null_mut(), &timeout) };
+    (signal > 0).then_some(signal)
+}
+
+// Return `true` if the application stopped: when `stopping`, once none of
+// `programs` runs anymore; otherwise, once all of them finished or failed.
+fn application_stopped(
+    a: &ApplicationState,
+    programs: &[String],
+    stopping: bool,
+) -> bool {
+    let stopped = |name: &String| {
+        a.programs.get(name).map_or(stopping, |s| match s.status {
+            ProgramStatus::Running => false,
+            ProgramStatus::Fatal => true,
+            _ => stopping || s.finished,
+        })
+    };
+    !programs.is_empty() && programs.iter().all(stopped)
+}
+
+// Exit code of supers once the application stopped: 128 plus the signal
+// that stopped it, if any; otherwise, the code of the last run of the first
+// program, by name, that failed, or 0.
+fn exit_code(a: &ApplicationState, signal: Option<i32>) -> i32 {
+    if let Some(signal) = signal {
+        return 128 + signal;
+    }
+    let mut names: Vec<_> = a.programs.keys().collect();
+    names.sort();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let state = &a.programs[name];
+            if state.status == ProgramStatus::Fatal {
+                return Some(1);
+            }
+            let run = state.history.back()?;
+            match (run.reason?, run.exit_code, run.signal) {
+                (EndReason::Stopped | EndReason::Lost, _, _) => None,
+                (EndReason::OomKilled, _, _) => Some(128 + libc::SIGKILL),
+                (_, Some(code), _) => Some(code),
+                (_, None, Some(signal)) => Some(128 + signal),
+                (_, None, None) => None,
+            }
+        })
+        .find(|code| *code != 0)
+        .unwrap_or(0)
+}
+
+// Send the signal of `msg` to the children of all programs.
+fn forward(
+    channels: &HashMap<String, Sender<CommandRequest>>,
+    msg: CommandMsg,
+) {
+    debug!(?msg, "forwarding signal");
+    for (name, tx) in channels {
+        if tx.send(msg.clone().into()).is_err() {
+            warn!(program = name, ?msg, "cannot forward signal");
+        }
+    }
+}
+
+#[instrument(level = "debug", skip_all)]
+fn run_init(
+    app_state: Arc<Mutex<ApplicationState>>,
+    channels: HashMap<String, Sender<CommandRequest>>,
+    stop_timeout: Duration,
+    state_dir: Option<PathBuf>,
+) {
+    let set = signal_set();
+    let mut programs: Vec<String> = channels.keys().cloned().collect();
+    programs.sort();
+    // The signal stopping the application, and when to kill the children
+    let mut stopping: Option<(i32, Option<Instant>)> = None;
+    let mut last_reap = Instant::now();
+    loop {
+        let reap = match wait_signal(&set, TICK) {
+            Some(libc::SIGCHLD) => true,
+            Some(signal) if STOP_SIGNALS.contains(&signal) => {
+                // Stopping signals reach all the processes of the programs,
+                // like the stops of the programs do.
+                forward(&channels, CommandMsg::SignalAll(signal));
+                if stopping.is_none() {
+                    info!(signal, "stopping the application");
+                    app_state.lock().unwrap().application_status =
+                        ApplicationStatus::Stopping;
+                    stopping =
+                        Some((signal, Some(Instant::now() + stop_timeout)));
+                }
+                false
+            }
+            Some(signal) => {
+                forward(&channels, CommandMsg::Signal(signal));
+                false
+            }
+            None => last_reap.elapsed() >= REAP_INTERVAL,
+        };
+        if reap {
+            reap_orphans();
+            last_reap = Instant::now();
+        }
+        if let Some((signal, Some(deadline))) = stopping {
+            if Instant::now() >= deadline {
+                warn!(?stop_timeout, "children did not exit; killing them");
+                forward(&channels, CommandMsg::SignalAll(libc::SIGKILL));
+                stopping = Some((signal, None));
+            }
+        }
+        let a = app_state.lock().unwrap();
+        if application_stopped(&a, &programs, stopping.is_some()) {
+            let code = exit_code(&a, stopping.map(|(signal, _)| signal));
+            drop(a);
+            if let Some(state_dir) = &state_dir {
+                persist::save(state_dir, &app_state, &mut vec![])
+                    .unwrap_or_else(|e| warn!(error = %e, "cannot save state"));
+            }
+            info!(code, "application stopped; exiting");
+            process::exit(code);
+        }
+    }
+}
+
+/// Start the thread of init mode, which reaps orphans, forwards signals to the
+/// children of the programs through their `channels`, stops the application
+/// on `SIGTERM`, `SIGINT` and `SIGQUIT`, and exits supers once the
+/// application stopped. `block_signals` must be called first.
+pub fn start_init_thread(
+    app_state: Arc<Mutex<ApplicationState>>,
+    channels: HashMap<String, Sender<CommandRequest>>,
+    stop_timeout: Duration,
+    state_dir: Option<PathBuf>,
+) -> Result<JoinHandle<()>, SupersError> {
+    set_subreaper()
+        .unwrap_or_else(|e| warn!(error = %e, "cannot become a subreaper"));
+    thread::Builder::new()
+        .name("init".into())
+        .spawn(move || run_init(app_state, channels, stop_timeout, state_dir))
+        .map_err(|e| SupersError::ProgramThreadStartError("init".into(), e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        application_stopped, exit_code, reap, release, spawn, zombie_children,
+    };
+    use crate::state::{
+        ApplicationState, EndReason, ProgramState, ProgramStatus,
+    };
+    use anyhow::Result;
+    use std::{
+        collections::HashMap,
+        os::unix::process::ExitStatusExt,
+        process::{Command, ExitStatus},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_zombie_children() -> Result<()> {
+        let mut child = Command::new("true").spawn()?;
+        let start = Instant::now();
+        while !zombie_children().contains(&child.id()) {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        child.wait()?;
+        assert!(!zombie_children().contains(&child.id()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reap() -> Result<()> {
+        let wait_zombie = |pid: u32| {
+            let start = Instant::now();
+            while !zombie_children().contains(&pid) {
+                assert!(start.elapsed() < Duration::from_secs(5));
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+        // Held children are left for supers to wait for
+        let mut held = spawn(&mut Command::new("true"))?;
+        wait_zombie(held.id());
+        reap(vec![held.id()]);
+        assert!(zombie_children().contains(&held.id()));
+        assert!(held.wait()?.success());
+        release(held.id());
+
+        let mut orphan = Command::new("true").spawn()?;
+        wait_zombie(orphan.id());
+        reap(vec![orphan.id()]);
+        assert!(!zombie_children().contains(&orphan.id()));
+        // The exit status was collected by the reaper
+        assert!(orphan.wait().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_application_stopped() {
+        let exited = |raw: i32, reason: EndReason| {
+            let mut s = ProgramState::default();
+            s.record_start(10, 10);
+            s.record_end(ExitStatus::from_raw(raw), reason);
+            s
+        };
+        let programs = vec!["api".to_string(), "job".to_string()];
+        let mut a = ApplicationState {
+            programs: HashMap::from([
+                ("api".into(), exited(9, EndReason::Stopped)),
+                ("job".into(), exited(0, EndReason::Exited)),
+            ]),
+            ..Default::default()
+        };
+        assert!(!application_stopped(&a, &[], true));
+        // Only a termination signal stops programs that may run again
+        assert!(!application_stopped(&a, &programs, false));
+        assert!(application_stopped(&a, &programs, true));
+        assert_eq!(exit_code(&a, Some(libc::SIGTERM)), 143);
+
+        a.programs.get_mut("api").unwrap().finished = true;
+        a.programs.get_mut("job").unwrap().finished = true;
+        assert!(application_stopped(&a, &programs, false));
+        assert_eq!(exit_code(&a, None), 0);
+        a.programs
+            .insert("job".into(), exited(3 << 8, EndReason::Exited));
+        assert_eq!(exit_code(&a, None), 3);
+        a.programs.get_mut("api").unwrap().status = ProgramStatus::Fatal;
+        assert_eq!(exit_code(&a, None), 1);
+
+        a.programs.get_mut("job").unwrap().status = ProgramStatus::Running;
+        assert!(!application_stopped(&a, &programs, true));
+    }
+}
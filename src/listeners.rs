@@ -0,0 +1,260 @@
+use std::{
+    io::{self, Read, Write},
+    process::Child,
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::Receiver;
+use tracing::{debug, warn};
+
+use crate::{config::ListenerConfig, events::Event};
+
+// Number of times an event is written to a listener that exits or does not
+// answer before the event is dropped.
+const MAX_DELIVERIES: u32 = 3;
+
+// Event listeners
+// ===============
+
+// Event written to a listener and waiting for its answer.
+#[derive(Debug)]
+struct InFlight {
+    event: Event,
+    // Number of times the event was written
+    deliveries: u32,
+    // Child the event was written to
+    pid: u32,
+    deadline: Instant,
+}
+
+/// Feeds the events received on `events` to the child of a listener program,
+/// one at a time: the next event is written once the child answered the
+/// previous one.
+#[derive(Debug)]
+pub struct Listener {
+    config: ListenerConfig,
+    events: Receiver<Event>,
+    in_flight: Option<InFlight>,
+    // Event to write again, since the child it was written to failed, and
+    // the number of times it was written
+    retry: Option<(Event, u32)>,
+    // Output of the child not yet split into lines
+    output: Vec<u8>,
+}
+
+impl Listener {
+    pub fn new(config: ListenerConfig, events: Receiver<Event>) -> Self {
+        Self {
+            config,
+            events,
+            in_flight: None,
+            retry: None,
+            output: vec![],
+        }
+    }
+
+    /// Check the answer of `child` to the event in flight, or write it the
+    /// next event. Return an error if the child must be restarted because it
+    /// does not answer or cannot be written to.
+    pub fn poll(&mut self, child: &mut Child) -> Result<(), String> {
+        match &self.in_flight {
+            // The child the event was written to exited without answering.
+            Some(f) if f.pid != child.id() => {
+                warn!(
+                    id = f.event.id,
+                    "listener exited before answering event"
+                );
+                self.requeue();
+            }
+            Some(_) => return self.read_answer(child),
+            None => {}
+        }
+        let (event, deliveries) = match self.retry.take() {
+            Some(retry) => retry,
+            None => {
+                let Some(event) = self.events.try_iter().find(|e| {
+                    e.matches(&self.config.events, &self.config.programs)
+                }) else {
+                    return Ok(());
+                };
+                (event, 0)
+            }
+        };
+        let mut line = serde_json::to_vec(&event).map_err(|e| e.to_string())?;
+        line.push(b'\n');
+        let id = event.id;
+        self.in_flight = Some(InFlight {
+            event,
+            deliveries: deliveries + 1,
+            pid: child.id(),
+            deadline: Instant::now()
+                + Duration::from_secs(self.config.ack_timeout_secs),
+        });
+        let Some(stdin) = child.stdin.as_mut() else {
+            self.requeue();
+            return Err("listener has no stdin".into());
+        };
+        if let Err(e) = stdin.write_all(&line) {
+            self.requeue();
+            return Err(format!("cannot write event {id}: {e}"));
+        }
+        debug!(id, "event written to listener");
+        Ok(())
+    }
+
+    // Take the event in flight back, to write it again to the next child,
+    // unless it was written `MAX_DELIVERIES` times already.
+    fn requeue(&mut self) {
+        self.output.clear();
+        let Some(f) = self.in_flight.take() else {
+            return;
+        };
+        if f.deliveries >= MAX_DELIVERIES {
+            warn!(id = f.event.id, f.deliveries, "dropping event");
+            return;
+        }
+        self.retry = Some((f.event, f.deliveries));
+    }
+
+    // Read the answer of `child` to the event in flight from its non-blocking
+    // stdout.
+    fn read_answer(&mut self, child: &mut Child) -> Result<(), String> {
+        let Some(in_flight) = &self.in_flight else {
+            return Ok(());
+        };
+        let id = in_flight.event.id;
+        let stdout = child.stdout.as_mut().ok_or("listener has no stdout")?;
+        let mut buf = [0; 1024];
+        loop {
+            match stdout.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => self.output.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(format!("cannot read answer: {e}")),
+            }
+        }
+        if let Some(end) = self.output.iter().position(|b| *b == b'\n') {
+            let answer: Vec<u8> = self.output.drain(..=end).collect();
+            let answer = String::from_utf8_lossy(&answer);
+            match answer.trim() {
+                "OK" => debug!(id, "listener handled event"),
+                answer => warn!(id, answer, "listener failed to handle event"),
+            }
+            self.in_flight = None;
+        } else if Instant::now() >= in_flight.deadline {
+            self.requeue();
+            return Err(format!(
+                "no answer to event {id} within {}s",
+                self.config.ack_timeout_secs
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Listener;
+    use crate::{
+        config::{ListenerConfig, ProgramConfig},
+        events::{Event, EventKind},
+        programs::start_child_program,
+    };
+    use anyhow::Result;
+    use crossbeam::channel::unbounded;
+    use std::{
+        fs, thread,
+        time::{Duration, Instant, SystemTime},
+    };
+
+    fn event(id: u64, program: &str) -> Event {
+        Event {
+            id,
+            timestamp: SystemTime::UNIX_EPOCH,
+            program: program.into(),
+            kind: EventKind::Spawned { pid: 1 },
+        }
+    }
+
+    #[test]
+    fn test_listener() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("events");
+        let config = ListenerConfig {
+            programs: vec!["api".into()],
+            ack_timeout_secs: 1,
+            ..Default::default()
+        };
+        let p = ProgramConfig {
+            name: "listener".into(),
+            cmd: "/bin/sh".into(),
+            args: vec![
+                "-c".into(),
+                format!(
+                    "while read -r e; do echo \"$e\" >> {}; echo OK; done",
+                    file.display()
+                ),
+            ],
+            listener: Some(config.clone()),
+            ..Default::default()
+        };
+        let (tx, rx) = unbounded();
+        let mut listener = Listener::new(config, rx);
+        let mut child = start_child_program(&p)?;
+        for (id, program) in [(1, "api"), (2, "web"), (3, "api")] {
+            tx.send(event(id, program))?;
+        }
+        let start = Instant::now();
+        while !listener.events.is_empty() || listener.in_flight.is_some() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            listener.poll(&mut child).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        let lines = fs::read_to_string(&file)?;
+        let ids: Vec<_> = lines
+            .lines()
+            .map(|l| {
+                serde_json::from_str::<serde_json::Value>(l).unwrap()["id"]
+                    .clone()
+            })
+            .collect();
+        assert_eq!(ids, vec![1, 3]);
+        child.kill()?;
+        child.wait()?;
+
+        // A listener that does not answer must be restarted
+        let silent = ProgramConfig {
+            args: vec!["-c".into(), "cat > /dev/null".into()],
+            ..p.clone()
+        };
+        let mut child = start_child_program(&silent)?;
+        tx.send(event(4, "api"))?;
+        listener.poll(&mut child).unwrap();
+        assert!(listener.in_flight.is_some());
+        let start = Instant::now();
+        while listener.poll(&mut child).is_ok() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(900));
+        assert!(listener.in_flight.is_none());
+        child.kill()?;
+        child.wait()?;
+
+        // The event goes to the next child instead
+        assert_eq!(listener.retry.as_ref().map(|(e, _)| e.id), Some(4));
+        let mut child = start_child_program(&p)?;
+        let start = Instant::now();
+        while listener.retry.is_some() || listener.in_flight.is_some() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            listener.poll(&mut child).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        let lines = fs::read_to_string(&file)?;
+        assert!(lines.lines().last().unwrap().contains("\"id\":4"));
+        child.kill()?;
+        child.wait()?;
+        Ok(())
+    }
+}
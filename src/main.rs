@@ -3,25 +3,38 @@ use actix_web::web::Data;
 use actix_web::{App, HttpServer};
 use log::init_tracing;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tracing::info;
 use tracing_actix_web::TracingLogger;
 
 use crossbeam::channel::Sender;
 
 use errors::SupersError;
-use messages::CommandMsg;
+use messages::{CommandMsg, CommandRequest};
 use state::{ApplicationState, ApplicationStatus};
 
 use programs::start_program_threads;
 
+mod cgroup;
 mod config;
+mod env;
 mod errors;
+mod events;
 mod handlers;
+mod hooks;
+mod init;
+mod listeners;
 mod log;
 mod messages;
+mod notifications;
+mod persist;
+mod process;
 mod programs;
+mod schedule;
 mod state;
+mod watch;
 // TODO: This is just a module for playing with ideas. Remove before production.
 mod playground;
 
@@ -33,6 +46,7 @@ pub fn get_test_app_config() -> ApplicationConfig {
         args: vec![String::from("3")],
         env: HashMap::new(),
         restartpolicy: RestartPolicy::Always,
+        ..Default::default()
     };
 
     let mut envs = HashMap::new();
@@ -48,6 +62,7 @@ pub fn get_test_app_config() -> ApplicationConfig {
         ],
         env: envs,
         restartpolicy: RestartPolicy::Never,
+        ..Default::default()
     };
 
     let mut envs2 = HashMap::new();
@@ -59,6 +74,7 @@ pub fn get_test_app_config() -> ApplicationConfig {
         args: vec![],
         env: envs2,
         restartpolicy: RestartPolicy::OnError,
+        ..Default::default()
     };
 
     ApplicationConfig {
@@ -78,7 +94,15 @@ pub fn start_server_thread() -> Result<(), SupersError> {
 #[derive(Clone)]
 pub struct WebAppState {
     app_state: Arc<Mutex<ApplicationState>>,
-    channels: HashMap<String, Sender<CommandMsg>>,
+    channels: HashMap<String, Sender<CommandRequest>>,
+    /// Names of the replicas of each program with `replicas` set
+    replicas: HashMap<String, Vec<String>>,
+    /// Names of the programs in each group
+    groups: HashMap<String, Vec<String>>,
+    /// Configuration of each program
+    programs: HashMap<String, ProgramConfig>,
+    /// Directory where the state of the programs is saved, if any
+    state_dir: Option<PathBuf>,
 }
 
 #[actix_web::main]
@@ -87,43 +111,141 @@ async fn main() -> Result<(), SupersError> {
 
     let app_config = ApplicationConfig::from_sources()?;
 
+    // as PID 1, supers must reap orphans and handle signals itself
+    let init_mode = app_config.init || std::process::id() == 1;
+    if init_mode {
+        init::block_signals()?;
+    }
+
+    // restore the state of the programs saved by a previous run of supers
+    let saved = match &app_config.state_dir {
+        Some(state_dir) => persist::load(state_dir),
+        None => Default::default(),
+    };
+    let restored = app_config
+        .programs
+        .iter()
+        .filter(|p| saved.programs.contains_key(&p.name))
+        .map(|p| (p.name.clone(), saved.restore(&p.name)))
+        .collect();
+
+    // keep the reaper away from the children handed over to this instance
+    for handover in saved.programs.values().filter_map(|p| p.handover) {
+        init::hold(handover.pid);
+    }
+
     // create the app_state container with statuses for the application status and the programs
     let app_state = Arc::new(Mutex::new(ApplicationState {
         application_status: ApplicationStatus::Running,
-        programs: HashMap::new(),
+        programs: restored,
+        events: Default::default(),
     }));
 
+    let replicas = app_config.replica_sets();
+    let groups = app_config.groups();
+    let programs = app_config
+        .programs
+        .iter()
+        .map(|p| (p.name.clone(), p.clone()))
+        .collect();
+
+    // scheduled programs start on their schedule, not with the application
+    let scheduled: Vec<String> = app_config
+        .programs
+        .iter()
+        .filter(|p| p.schedule.is_some())
+        .map(|p| p.name.clone())
+        .collect();
+
+    // start the threads notifying the events of the programs
+    let _notifiers = notifications::start_notification_threads(
+        &app_config.notifications,
+        &app_state,
+    )?;
+
+    // programs stopped by an operator stay stopped
+    let stopped: Vec<String> = saved
+        .programs
+        .iter()
+        .filter(|(_, p)| p.stopped_by_operator)
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    // keep saving the state of the programs
+    let _saver = match &app_config.state_dir {
+        Some(state_dir) => Some(persist::start_save_thread(
+            state_dir.clone(),
+            app_state.clone(),
+        )?),
+        None => None,
+    };
+
     // start the threads for the programs configured the application
     let (_threads, channels) =
         start_program_threads(app_config.programs, &app_state).unwrap();
 
     // send a start message to all programs
-    for sx in channels.values() {
-        sx.send(CommandMsg::Start)?;
+    for (name, sx) in &channels {
+        if stopped.contains(name) {
+            info!(program = name, "program was stopped by an operator");
+            sx.send(CommandMsg::Stop.into())?;
+        } else if !scheduled.contains(name) {
+            sx.send(CommandMsg::Start.into())?;
+        }
     }
+    // reap orphans, forward signals and exit when the application stops
+    let _init = if init_mode {
+        Some(init::start_init_thread(
+            app_state.clone(),
+            channels.clone(),
+            Duration::from_secs(app_config.stop_timeout_secs),
+            app_config.state_dir.clone(),
+        )?)
+    } else {
+        None
+    };
+
     // create the webapp state object with the command hannels used to communicate with the threads
     let webapp_state = WebAppState {
         app_state,
         channels,
+        replicas,
+        groups,
+        programs,
+        state_dir: app_config.state_dir,
     };
 
     // Start the HTTP server
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .wrap(actix_web::middleware::Logger::default())
             .wrap(TracingLogger::default())
             .app_data(Data::new(webapp_state.clone()))
             .service(handlers::ready)
             .service(handlers::get_app_status)
+            .service(handlers::handover)
             .service(handlers::get_programs)
             .service(handlers::get_program)
+            .service(handlers::get_program_history)
             .service(handlers::start_program)
             .service(handlers::stop_program)
             .service(handlers::restart_program)
+            .service(handlers::signal_program)
+            .service(handlers::write_program_stdin)
+            .service(handlers::start_group)
+            .service(handlers::stop_group)
+            .service(handlers::restart_group)
+            .service(handlers::rolling_restart_group)
+            .service(handlers::get_events)
     })
-    .bind((app_config.address, app_config.port))?
-    .run()
-    .await?;
+    .bind((app_config.address, app_config.port))?;
+    // in init mode, the init thread handles the signals
+    let server = if init_mode {
+        server.disable_signals()
+    } else {
+        server
+    };
+    server.run().await?;
 
     Ok(())
 }
@@ -1,7 +1,98 @@
+use crossbeam::channel::{bounded, Receiver, Sender};
+use std::fmt;
+
 /// Messages sent on the command channel
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq)]
 pub enum CommandMsg {
     Start,
     Stop,
     Restart,
+    /// Send a signal to the child, if any
+    Signal(i32),
+    /// Send a signal to the child and, following `kill_mode`, to the rest of
+    /// its processes, as supers does when it stops the child
+    SignalAll(i32),
+    /// Write bytes to the stdin of the child, if any
+    Stdin(Vec<u8>),
+    /// Hand the child over to the next instance of supers
+    Handover,
+}
+
+// Input for the child is not logged, since it may hold secrets.
+impl fmt::Debug for CommandMsg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandMsg::Start => write!(f, "Start"),
+            CommandMsg::Stop => write!(f, "Stop"),
+            CommandMsg::Restart => write!(f, "Restart"),
+            CommandMsg::Signal(signal) => write!(f, "Signal({signal})"),
+            CommandMsg::SignalAll(signal) => write!(f, "SignalAll({signal})"),
+            CommandMsg::Stdin(data) => write!(f, "Stdin({} bytes)", data.len()),
+            CommandMsg::Handover => write!(f, "Handover"),
+        }
+    }
+}
+
+impl CommandMsg {
+    /// Return `true` if the command acts on the lifecycle of the program,
+    /// rather than on its running child.
+    pub fn is_lifecycle(&self) -> bool {
+        !matches!(
+            self,
+            CommandMsg::Signal(_)
+                | CommandMsg::SignalAll(_)
+                | CommandMsg::Stdin(_)
+                | CommandMsg::Handover
+        )
+    }
+}
+
+/// Outcome of a command, reported by the program thread
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandReply {
+    /// A child was spawned with this pid
+    Spawned(u32),
+    /// The child was already running
+    AlreadyRunning,
+    /// The child was stopped
+    Stopped,
+    /// There was no running child
+    NotRunning,
+    /// The signal or input was delivered to the child
+    Delivered,
+    /// The spawn waits for the dependencies of the program to complete
+    WaitingForDependencies,
+    /// The child could not be spawned
+    SpawnError(String),
+    /// The input was not delivered to the child, for this reason
+    Rejected(String),
+    /// The child with this pid is handed over to the next instance of supers
+    HandedOver(u32),
+}
+
+/// A command for a program thread, with an optional channel for its outcome
+#[derive(Debug, Clone)]
+pub struct CommandRequest {
+    pub msg: CommandMsg,
+    pub reply: Option<Sender<CommandReply>>,
+}
+
+impl CommandRequest {
+    /// Create a request for `msg` and the channel receiving its outcome.
+    pub fn with_reply(msg: CommandMsg) -> (Self, Receiver<CommandReply>) {
+        let (tx, rx) = bounded(1);
+        (
+            CommandRequest {
+                msg,
+                reply: Some(tx),
+            },
+            rx,
+        )
+    }
+}
+
+impl From<CommandMsg> for CommandRequest {
+    fn from(msg: CommandMsg) -> Self {
+        CommandRequest { msg, reply: None }
+    }
 }
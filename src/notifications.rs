@@ -0,0 +1,426 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use serde_json::Value;
+use tracing::{debug, error, instrument, warn};
+
+use crate::{
+    config::NotificationConfig,
+    errors::SupersError,
+    events::{self, Event, EventKind},
+    state::ApplicationState,
+};
+
+// Placeholders accepted in the template of a notification, besides `json`.
+const TEMPLATE_FIELDS: [&str; 10] = [
+    "id",
+    "timestamp",
+    "program",
+    "event",
+    "pid",
+    "status",
+    "code",
+    "requested",
+    "delay_ms",
+    "error",
+];
+// Number of events waiting to be notified, beyond which the oldest ones are
+// dropped.
+const NOTIFICATION_QUEUE: usize = 100;
+
+// Webhook notifications
+// =====================
+
+// Call `f` with the name of each `{{...}}` placeholder in `template` and
+// build the result from its values.
+fn render(
+    template: &str,
+    mut f: impl FnMut(&str) -> Result<String, String>,
+) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let len = rest[start..].find("}}").ok_or_else(|| {
+            format!("unterminated placeholder in {template:?}")
+        })?;
+        out.push_str(&rest[..start]);
+        out.push_str(&f(rest[start + 2..start + len].trim())?);
+        rest = &rest[start + len + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Check the configuration of notification `n`.
+pub fn validate(n: &NotificationConfig) -> Result<(), SupersError> {
+    let err = |e: String| {
+        SupersError::ApplicationConfigError(format!(
+            "invalid notification for {}: {e}",
+            n.url
+        ))
+    };
+    if !n.url.starts_with("http://") && !n.url.starts_with("https://") {
+        return Err(err("the url must be http or https".into()));
+    }
+    events::validate_names(&n.events).map_err(err)?;
+    if let Some(template) = &n.template {
+        render(template, |field| match field {
+            "json" => Ok(String::new()),
+            f if TEMPLATE_FIELDS.contains(&f) => Ok(String::new()),
+            f => Err(format!("unknown placeholder {f:?}")),
+        })
+        .map_err(err)?;
+    }
+    Ok(())
+}
+
+/// Return `true` if `event` passes the filters of notification `n`. Only
+/// fatal errors and failed exits that nobody requested are errors.
+pub fn matches(n: &NotificationConfig, event: &Event) -> bool {
+    let is_error = match &event.kind {
+        EventKind::Exited {
+            code, requested, ..
+        } => !requested && *code != Some(0),
+        EventKind::Fatal { .. } => true,
+        _ => false,
+    };
+    event.matches(&n.events, &n.programs) && (!n.only_errors || is_error)
+}
+
+/// The body of the request notifying `event`: the event as JSON, or rendered
+/// with `template`. String fields are escaped for JSON, and fields that the
+/// event lacks render as `null`.
+pub fn render_payload(
+    template: Option<&str>,
+    event: &Event,
+) -> Result<String, String> {
+    let text = serde_json::to_string(event).map_err(|e| e.to_string())?;
+    let Some(template) = template else {
+        return Ok(text);
+    };
+    let json = serde_json::to_value(event).map_err(|e| e.to_string())?;
+    render(template, |field| {
+        Ok(match (field, json.get(field)) {
+            ("json", _) => text.clone(),
+            (_, Some(Value::String(s))) => {
+                let quoted = Value::String(s.clone()).to_string();
+                quoted[1..quoted.len() - 1].to_string()
+            }
+            (_, Some(value)) => value.to_string(),
+            (_, None) => "null".into(),
+        })
+    })
+}
+
+// Send `payload` to the url of notification `n`, retrying on network errors
+// and server errors with the backoff of the notification, waiting each delay
+// with `wait`.
+fn deliver(
+    n: &NotificationConfig,
+    payload: &str,
+    mut wait: impl FnMut(Duration),
+) -> Result<(), String> {
+    let mut attempt = 0;
+    loop {
+        let result = ureq::post(&n.url)
+            .timeout(Duration::from_secs(n.timeout_secs))
+            .set("Content-Type", "application/json")
+            .send_string(payload);
+        let (error, retry) = match result {
+            Ok(_) => return Ok(()),
+            Err(ureq::Error::Status(code, _)) => {
+                (format!("status {code}"), code >= 500 || code == 429)
+            }
+            Err(e) => (e.to_string(), true),
+        };
+        if !retry || attempt == n.retries {
+            return Err(error);
+        }
+        attempt += 1;
+        let delay = n.backoff.delay(attempt);
+        warn!(url = n.url, error, ?delay, "notification failed; retrying");
+        wait(delay);
+    }
+}
+
+// Queue `event` for notification `n` if it passes its filters, dropping the
+// oldest event of a full `queue`.
+fn enqueue(n: &NotificationConfig, queue: &mut VecDeque<Event>, event: Event) {
+    if !matches(n, &event) {
+        return;
+    }
+    if queue.len() >= NOTIFICATION_QUEUE {
+        if let Some(dropped) = queue.pop_front() {
+            warn!(id = dropped.id, "notifications are behind; dropping event");
+        }
+    }
+    queue.push_back(event);
+}
+
+// Queue the events received on `events` until `deadline`.
+fn receive_until(
+    n: &NotificationConfig,
+    queue: &mut VecDeque<Event>,
+    events: &Receiver<Event>,
+    deadline: Instant,
+) {
+    loop {
+        match events.recv_deadline(deadline) {
+            Ok(event) => enqueue(n, queue, event),
+            Err(RecvTimeoutError::Timeout) => return,
+            Err(RecvTimeoutError::Disconnected) => {
+                thread::sleep(
+                    deadline.saturating_duration_since(Instant::now()),
+                );
+                return;
+            }
+        }
+    }
+}
+
+#[instrument(level = "debug", skip_all, fields(url = n.url))]
+fn notify(n: &NotificationConfig, events: Receiver<Event>) {
+    let mut queue = VecDeque::new();
+    loop {
+        if queue.is_empty() {
+            let Ok(event) = events.recv() else {
+                return;
+            };
+            enqueue(n, &mut queue, event);
+        }
+        while let Ok(event) = events.try_recv() {
+            enqueue(n, &mut queue, event);
+        }
+        let Some(event) = queue.pop_front() else {
+            continue;
+        };
+        debug!(id = event.id, "notifying event");
+        // Events keep arriving while the delivery waits to be retried.
+        let wait = |delay| {
+            receive_until(n, &mut queue, &events, Instant::now() + delay)
+        };
+        let result = render_payload(n.template.as_deref(), &event)
+            .and_then(|payload| deliver(n, &payload, wait));
+        if let Err(e) = result {
+            error!(id = event.id, error = e, "cannot notify event");
+        }
+    }
+}
+
+/// Start a thread for each notification, sending the events published from
+/// now on to its webhook.
+pub fn start_notification_threads(
+    notifications: &[NotificationConfig],
+    app_state: &Arc<Mutex<ApplicationState>>,
+) -> Result<Vec<JoinHandle<()>>, SupersError> {
+    notifications
+        .iter()
+        .enumerate()
+        .map(|(i, n)| {
+            let n = n.clone();
+            let events = app_state.lock().unwrap().events.subscribe();
+            thread::Builder::new()
+                .name(format!("notification-{i}"))
+                .spawn(move || notify(&n, events))
+                .map_err(|e| {
+                    SupersError::ProgramThreadStartError(
+                        format!("notification-{i}"),
+                        e,
+                    )
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        deliver, enqueue, matches, render_payload, validate, NOTIFICATION_QUEUE,
+    };
+    use crate::{
+        config::{Backoff, NotificationConfig},
+        events::{Event, EventKind},
+    };
+    use anyhow::Result;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread,
+        time::SystemTime,
+    };
+
+    fn event(kind: EventKind) -> Event {
+        Event {
+            id: 7,
+            timestamp: SystemTime::UNIX_EPOCH,
+            program: "api".into(),
+            kind,
+        }
+    }
+
+    fn exited(code: Option<i32>, requested: bool) -> Event {
+        event(EventKind::Exited {
+            status: "exit status".into(),
+            code,
+            requested,
+        })
+    }
+
+    #[test]
+    fn test_validate() {
+        let n = |url: &str, events: &[&str], template: Option<&str>| {
+            NotificationConfig {
+                url: url.into(),
+                events: events.iter().map(|e| e.to_string()).collect(),
+                template: template.map(Into::into),
+                ..Default::default()
+            }
+        };
+        assert!(validate(&n("http://localhost/x", &["fatal"], None)).is_ok());
+        assert!(validate(&n("localhost/x", &[], None)).is_err());
+        assert!(validate(&n("http://localhost/x", &["boom"], None)).is_err());
+        let template = Some(r#"{"text": "{{program}} {{ event }}: {{json}}"}"#);
+        assert!(validate(&n("http://localhost/x", &[], template)).is_ok());
+        let template = Some("{{program}} {{name}}");
+        assert!(validate(&n("http://localhost/x", &[], template)).is_err());
+        assert!(validate(&n("http://localhost/x", &[], Some("{{x"))).is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let n = NotificationConfig {
+            events: vec!["exited".into(), "fatal".into()],
+            only_errors: true,
+            ..Default::default()
+        };
+        assert!(matches(&n, &exited(Some(1), false)));
+        assert!(matches(&n, &exited(None, false)));
+        assert!(!matches(&n, &exited(Some(0), false)));
+        assert!(!matches(&n, &exited(None, true)));
+        assert!(matches(&n, &event(EventKind::Fatal { error: "e".into() })));
+        assert!(!matches(&n, &event(EventKind::Spawned { pid: 1 })));
+        // Without a filter on events, only errors pass
+        let n = NotificationConfig {
+            only_errors: true,
+            ..Default::default()
+        };
+        assert!(matches(&n, &exited(Some(1), false)));
+        assert!(matches(&n, &event(EventKind::Fatal { error: "e".into() })));
+        assert!(!matches(&n, &event(EventKind::Spawned { pid: 1 })));
+        assert!(!matches(&n, &event(EventKind::StopRequested)));
+        let restart = EventKind::RestartScheduled { delay_ms: 10 };
+        assert!(!matches(&n, &event(restart)));
+        let n = NotificationConfig {
+            programs: vec!["web".into()],
+            ..Default::default()
+        };
+        assert!(!matches(&n, &exited(Some(1), false)));
+    }
+
+    #[test]
+    fn test_enqueue() {
+        let n = NotificationConfig::default();
+        let mut queue = Default::default();
+        for id in 0..=NOTIFICATION_QUEUE as u64 {
+            enqueue(
+                &n,
+                &mut queue,
+                Event {
+                    id,
+                    ..exited(Some(1), false)
+                },
+            );
+        }
+        // The oldest event was dropped
+        assert_eq!(queue.len(), NOTIFICATION_QUEUE);
+        assert_eq!(queue.front().map(|e| e.id), Some(1));
+    }
+
+    #[test]
+    fn test_render_payload() -> Result<()> {
+        let e = event(EventKind::Fatal {
+            error: "bad \"cmd\"".into(),
+        });
+        assert_eq!(
+            render_payload(None, &e).unwrap(),
+            r#"{"id":7,"timestamp":"1970-01-01T00:00:00Z","program":"api","event":"fatal","error":"bad \"cmd\""}"#
+        );
+        let template =
+            r#"{"text": "{{program}} {{event}}: {{error}}", "pid": {{pid}}}"#;
+        let payload = render_payload(Some(template), &e).unwrap();
+        assert_eq!(
+            payload,
+            r#"{"text": "api fatal: bad \"cmd\"", "pid": null}"#
+        );
+        serde_json::from_str::<serde_json::Value>(&payload)?;
+        Ok(())
+    }
+
+    // Answer the requests received on `listener` with `statuses`, and return
+    // the bodies of the requests.
+    fn serve(listener: TcpListener, statuses: &[u16]) -> Result<Vec<String>> {
+        let mut bodies = vec![];
+        for status in statuses {
+            let (stream, _) = listener.accept()?;
+            let mut reader = BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line)?;
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse()?;
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body)?;
+            bodies.push(String::from_utf8(body)?);
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 {status} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            )?;
+        }
+        Ok(bodies)
+    }
+
+    #[test]
+    fn test_deliver() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let n = NotificationConfig {
+            url: format!("http://{}/hook", listener.local_addr()?),
+            retries: 2,
+            backoff: Backoff {
+                initial_ms: 10,
+                max_ms: 10,
+            },
+            ..Default::default()
+        };
+        // Server errors are retried
+        let server = thread::spawn(move || serve(listener, &[503, 500, 204]));
+        deliver(&n, r#"{"event":"fatal"}"#, thread::sleep).unwrap();
+        let bodies = server.join().unwrap()?;
+        assert_eq!(bodies, vec![r#"{"event":"fatal"}"#; 3]);
+
+        // Client errors are not, and retries are limited
+        for statuses in [&[400][..], &[500, 500, 500]] {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let n = NotificationConfig {
+                url: format!("http://{}/hook", listener.local_addr()?),
+                ..n.clone()
+            };
+            let server = thread::spawn(move || serve(listener, statuses));
+            assert!(deliver(&n, "{}", thread::sleep).is_err());
+            assert_eq!(server.join().unwrap()?.len(), statuses.len());
+        }
+        Ok(())
+    }
+}
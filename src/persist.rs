@@ -0,0 +1,248 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use serde_derive::{Deserialize, Serialize};
+use tracing::{debug, info, instrument, warn};
+
+use crate::{
+    errors::SupersError,
+    state::{
+        ApplicationState, EndReason, HandoverChild, ProgramState, RunRecord,
+    },
+};
+
+// Name of the state file in the state directory.
+const STATE_FILE: &str = "state.json";
+// Interval between checks of the state to save.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+// Serializes the writes of the save thread and of a handover.
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+// Durable state
+// =============
+
+/// State of a program that survives restarts of supers
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedProgram {
+    #[serde(default)]
+    pub stopped_by_operator: bool,
+    #[serde(default)]
+    pub starts: u64,
+    #[serde(default)]
+    pub history: VecDeque<RunRecord>,
+    #[serde(default)]
+    pub handover: Option<HandoverChild>,
+}
+
+/// State of the application that survives restarts of supers
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedState {
+    #[serde(default)]
+    pub programs: HashMap<String, SavedProgram>,
+}
+
+impl SavedState {
+    fn of(app_state: &ApplicationState) -> Self {
+        let programs = app_state
+            .programs
+            .iter()
+            .map(|(name, s)| {
+                let saved = SavedProgram {
+                    stopped_by_operator: s.stopped_by_operator,
+                    starts: s.starts,
+                    history: s.history.clone(),
+                    handover: s.handover,
+                };
+                (name.clone(), saved)
+            })
+            .collect();
+        Self { programs }
+    }
+
+    /// Restore the state of program `name`. Runs that were going on when the
+    /// state was saved are lost, except the run of a child handed over to
+    /// this instance of supers.
+    pub fn restore(&self, name: &str) -> ProgramState {
+        let Some(saved) = self.programs.get(name) else {
+            return Default::default();
+        };
+        let mut history = saved.history.clone();
+        let handover = saved.handover.map(|h| h.pid);
+        for run in history
+            .iter_mut()
+            .filter(|r| r.ended.is_none() && Some(r.pid) != handover)
+        {
+            run.reason = Some(EndReason::Lost);
+        }
+        ProgramState {
+            stopped_by_operator: saved.stopped_by_operator,
+            starts: saved.starts,
+            history,
+            handover: saved.handover,
+            ..Default::default()
+        }
+    }
+}
+
+fn state_file(state_dir: &Path) -> PathBuf {
+    state_dir.join(STATE_FILE)
+}
+
+fn error(path: &Path, e: impl ToString) -> SupersError {
+    SupersError::ApplicationStateFileError(
+        path.display().to_string(),
+        e.to_string(),
+    )
+}
+
+/// Load the state saved in `state_dir`, if any. A state that cannot be read
+/// is ignored, so that supers starts anyway, with the default state.
+#[instrument(level = "debug")]
+pub fn load(state_dir: &Path) -> SavedState {
+    let path = state_file(state_dir);
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!("no saved state");
+            return Default::default();
+        }
+        Err(e) => {
+            warn!(error = %error(&path, e), "ignoring saved state");
+            return Default::default();
+        }
+    };
+    serde_json::from_slice(&data).unwrap_or_else(|e| {
+        warn!(error = %error(&path, e), "ignoring saved state");
+        Default::default()
+    })
+}
+
+// Write `data` to `path` and flush it to the disk.
+fn write_synced(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+// Write `data` to the state file in `state_dir`, replacing the file
+// atomically so that a crash never leaves a partial state. The directory is
+// flushed as well, so that the new file survives a power loss.
+fn write(state_dir: &Path, data: &[u8]) -> Result<(), SupersError> {
+    let path = state_file(state_dir);
+    let tmp = path.with_extension("json.tmp");
+    let _guard = WRITE_LOCK.lock().unwrap();
+    fs::create_dir_all(state_dir)
+        .and_then(|_| write_synced(&tmp, data))
+        .and_then(|_| fs::rename(&tmp, &path))
+        .and_then(|_| File::open(state_dir)?.sync_all())
+        .map_err(|e| error(&path, e))
+}
+
+/// Save the state of the application to `state_dir` if it changed since the
+/// last save, whose data is `last`.
+pub fn save(
+    state_dir: &Path,
+    app_state: &Arc<Mutex<ApplicationState>>,
+    last: &mut Vec<u8>,
+) -> Result<(), SupersError> {
+    let saved = SavedState::of(&app_state.lock().unwrap());
+    let data = serde_json::to_vec(&saved)
+        .map_err(|e| error(&state_file(state_dir), e))?;
+    if data != *last {
+        write(state_dir, &data)?;
+        debug!("saved state");
+        *last = data;
+    }
+    Ok(())
+}
+
+/// Start a thread that saves the state of the application to `state_dir`
+/// whenever it changes.
+pub fn start_save_thread(
+    state_dir: PathBuf,
+    app_state: Arc<Mutex<ApplicationState>>,
+) -> Result<JoinHandle<()>, SupersError> {
+    info!(?state_dir, "saving state");
+    thread::Builder::new()
+        .name("save-state".into())
+        .spawn(move || {
+            let mut last = vec![];
+            loop {
+                if let Err(e) = save(&state_dir, &app_state, &mut last) {
+                    warn!(error = %e, "cannot save state");
+                }
+                thread::sleep(SAVE_INTERVAL);
+            }
+        })
+        .map_err(|e| {
+            SupersError::ProgramThreadStartError("save-state".into(), e)
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{load, save};
+    use crate::state::{ApplicationState, EndReason, ProgramState};
+    use anyhow::Result;
+    use std::{
+        collections::HashMap,
+        fs,
+        os::unix::process::ExitStatusExt,
+        process::ExitStatus,
+        sync::{Arc, Mutex},
+    };
+
+    #[test]
+    fn test_save_and_load() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let state_dir = dir.path().join("state");
+        assert!(load(&state_dir).programs.is_empty());
+
+        let mut api = ProgramState {
+            stopped_by_operator: true,
+            starts: 2,
+            ..Default::default()
+        };
+        api.record_start(10, 5);
+        api.record_end(ExitStatus::from_raw(256), EndReason::Exited);
+        api.record_start(11, 5);
+        let app_state = Arc::new(Mutex::new(ApplicationState {
+            programs: HashMap::from([("api".into(), api)]),
+            ..Default::default()
+        }));
+        let mut last = vec![];
+        save(&state_dir, &app_state, &mut last)?;
+        let modified =
+            fs::metadata(state_dir.join("state.json"))?.modified()?;
+        // An unchanged state is not written again
+        save(&state_dir, &app_state, &mut last)?;
+        assert_eq!(
+            fs::metadata(state_dir.join("state.json"))?.modified()?,
+            modified
+        );
+
+        let saved = load(&state_dir);
+        let api = saved.restore("api");
+        assert!(api.stopped_by_operator);
+        assert_eq!(api.starts, 2);
+        assert_eq!(api.history.len(), 2);
+        assert_eq!(api.history[0].exit_code, Some(1));
+        assert_eq!(api.history[0].reason, Some(EndReason::Exited));
+        // The run going on when the state was saved is lost
+        assert_eq!(api.history[1].pid, 11);
+        assert_eq!(api.history[1].reason, Some(EndReason::Lost));
+        assert!(!saved.restore("web").stopped_by_operator);
+
+        // A corrupt state is ignored
+        fs::write(state_dir.join("state.json"), "{")?;
+        assert!(load(&state_dir).programs.is_empty());
+        Ok(())
+    }
+}
@@ -20,6 +20,7 @@ mod test {
 
     #[test]
     #[ignore]
+    #[allow(clippy::zombie_processes)]
     fn test_child_match() -> Result<()> {
         let mut c: Option<std::process::Child> = None;
         let child = std::process::Command::new("ls").spawn().unwrap();
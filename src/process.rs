@@ -0,0 +1,663 @@
+use std::{
+    env,
+    ffi::CString,
+    fs, io, mem,
+    os::unix::process::{CommandExt, ExitStatusExt},
+    process::{Command, ExitStatus},
+    ptr,
+};
+
+use tracing::{debug, instrument};
+
+use crate::{
+    config::{ProgramConfig, Rlimit, RlimitValue, Rlimits},
+    errors::SupersError,
+};
+
+// Execution settings for child processes
+// ======================================
+
+// Size of the buffer handed to the reentrant `getpwnam_r`-family functions.
+const PW_BUFFER_SIZE: usize = 16384;
+
+/// Credentials for a child process, resolved from the program config.
+///
+/// `None` values leave the corresponding credential as inherited from supers.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub groups: Option<Vec<u32>>,
+}
+
+// Look up the uid and primary gid of a user by name or numeric id.
+fn lookup_user(user: &str) -> Option<(u32, u32)> {
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; PW_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    let rc = if let Ok(uid) = user.parse::<u32>() {
+        unsafe {
+            libc::getpwuid_r(
+                uid,
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        }
+    } else {
+        let name = CString::new(user).ok()?;
+        unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        }
+    };
+    (rc == 0 && !result.is_null()).then_some((pwd.pw_uid, pwd.pw_gid))
+}
+
+// Look up the gid of a group by name or numeric id.
+fn lookup_group(group: &str) -> Option<u32> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Some(gid);
+    }
+    let name = CString::new(group).ok()?;
+    let mut grp: libc::group = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; PW_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    let rc = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    (rc == 0 && !result.is_null()).then_some(grp.gr_gid)
+}
+
+impl Credentials {
+    /// Resolve the user, group and supplementary groups of program `p`.
+    ///
+    /// Return an error if any of them does not exist, or if supers does not
+    /// have the privileges to switch to them.
+    pub fn resolve(p: &ProgramConfig) -> Result<Self, SupersError> {
+        let err = |msg: String| {
+            SupersError::ProgramExecConfigError(p.name.clone(), msg)
+        };
+        let user = p
+            .user
+            .as_deref()
+            .map(|u| {
+                lookup_user(u).ok_or_else(|| err(format!("unknown user {u}")))
+            })
+            .transpose()?;
+        let gid = p
+            .run_as_group
+            .as_deref()
+            .map(|g| {
+                lookup_group(g).ok_or_else(|| err(format!("unknown group {g}")))
+            })
+            .transpose()?
+            .or(user.map(|(_, gid)| gid));
+        let groups = p
+            .supplementary_groups
+            .iter()
+            .map(|g| {
+                lookup_group(g).ok_or_else(|| err(format!("unknown group {g}")))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // When switching users, always replace the supplementary groups so
+        // the child does not keep the groups of supers.
+        let groups = (user.is_some() || !groups.is_empty()).then_some(groups);
+        let creds = Credentials {
+            uid: user.map(|(uid, _)| uid),
+            gid,
+            groups,
+        };
+        creds.check_privileges().map_err(err)?;
+        Ok(creds)
+    }
+
+    // Switching credentials requires root, unless they are already ours.
+    fn check_privileges(&self) -> Result<(), String> {
+        let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        if euid == 0 {
+            return Ok(());
+        }
+        let same_uid = self.uid.is_none_or(|uid| uid == euid);
+        let same_gid = self.gid.is_none_or(|gid| gid == egid);
+        if same_uid && same_gid && self.groups.is_none() {
+            Ok(())
+        } else {
+            Err(format!(
+                "supers runs as uid {euid} and lacks the permission to switch user or group"
+            ))
+        }
+    }
+
+    // Apply the credentials to the current process.
+    // Runs in the forked child, so it must only call async-signal-safe functions.
+    fn apply(&self) -> io::Result<()> {
+        if let Some(groups) = &self.groups {
+            if unsafe { libc::setgroups(groups.len() as _, groups.as_ptr()) }
+                != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(gid) = self.gid {
+            if unsafe { libc::setgid(gid) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(uid) = self.uid {
+            if unsafe { libc::setuid(uid) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse an octal umask such as `"022"` or `"0o027"`.
+pub fn parse_umask(umask: &str) -> Option<u32> {
+    u32::from_str_radix(umask.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|m| *m <= 0o777)
+}
+
+// Resolve the umask of program `p`, if any.
+fn resolve_umask(p: &ProgramConfig) -> Result<Option<u32>, SupersError> {
+    p.umask
+        .as_deref()
+        .map(|m| {
+            parse_umask(m).ok_or_else(|| {
+                SupersError::ProgramExecConfigError(
+                    p.name.clone(),
+                    format!("invalid umask {m}"),
+                )
+            })
+        })
+        .transpose()
+}
+
+/// Parse a signal given by name, with or without the `SIG` prefix, or by number.
+pub fn parse_signal(signal: &str) -> Option<i32> {
+    if let Ok(n) = signal.parse::<i32>() {
+        return (1..=64).contains(&n).then_some(n);
+    }
+    let name = signal.to_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    let signal = match name {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "ILL" => libc::SIGILL,
+        "TRAP" => libc::SIGTRAP,
+        "ABRT" => libc::SIGABRT,
+        "BUS" => libc::SIGBUS,
+        "FPE" => libc::SIGFPE,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "SEGV" => libc::SIGSEGV,
+        "USR2" => libc::SIGUSR2,
+        "PIPE" => libc::SIGPIPE,
+        "ALRM" => libc::SIGALRM,
+        "TERM" => libc::SIGTERM,
+        "CHLD" => libc::SIGCHLD,
+        "CONT" => libc::SIGCONT,
+        "STOP" => libc::SIGSTOP,
+        "TSTP" => libc::SIGTSTP,
+        "TTIN" => libc::SIGTTIN,
+        "TTOU" => libc::SIGTTOU,
+        "URG" => libc::SIGURG,
+        "XCPU" => libc::SIGXCPU,
+        "XFSZ" => libc::SIGXFSZ,
+        "VTALRM" => libc::SIGVTALRM,
+        "PROF" => libc::SIGPROF,
+        "WINCH" => libc::SIGWINCH,
+        "IO" => libc::SIGIO,
+        "SYS" => libc::SIGSYS,
+        _ => return None,
+    };
+    Some(signal)
+}
+
+/// Make writes to the file descriptor `fd` non-blocking.
+pub fn set_nonblocking(fd: i32) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0
+        || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) }
+            < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Send `signal` to the process `pid`.
+pub fn send_signal(pid: u32, signal: i32) -> io::Result<()> {
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Send `signal` to every process in the process group `pgid`.
+pub fn signal_process_group(pgid: u32, signal: i32) -> io::Result<()> {
+    if unsafe { libc::kill(-(pgid as libc::pid_t), signal) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Kill every process in the process group `pgid`.
+pub fn kill_process_group(pgid: u32) -> io::Result<()> {
+    signal_process_group(pgid, libc::SIGKILL)
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type Resource = libc::c_int;
+
+// List the resource limits that are set, paired with their name and their
+// `setrlimit` resource.
+fn resource_limits(rlimits: &Rlimits) -> Vec<(&'static str, Resource, Rlimit)> {
+    [
+        ("nofile", libc::RLIMIT_NOFILE, rlimits.nofile),
+        ("nproc", libc::RLIMIT_NPROC, rlimits.nproc),
+        ("core", libc::RLIMIT_CORE, rlimits.core),
+        ("as", libc::RLIMIT_AS, rlimits.r#as),
+        ("cpu", libc::RLIMIT_CPU, rlimits.cpu),
+        ("fsize", libc::RLIMIT_FSIZE, rlimits.fsize),
+        ("stack", libc::RLIMIT_STACK, rlimits.stack),
+    ]
+    .into_iter()
+    .filter_map(|(name, resource, limit)| limit.map(|l| (name, resource, l)))
+    .collect()
+}
+
+// Check that no soft limit exceeds its hard limit.
+fn validate_rlimits(p: &ProgramConfig) -> Result<(), SupersError> {
+    match resource_limits(&p.rlimits)
+        .into_iter()
+        .find(|(_, _, l)| l.soft > l.hard)
+    {
+        Some((name, _, l)) => Err(SupersError::ProgramExecConfigError(
+            p.name.clone(),
+            format!(
+                "soft limit {} exceeds hard limit {} for resource {name}",
+                l.soft, l.hard
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Check that supers can apply the execution settings of program `p`.
+pub fn validate(p: &ProgramConfig) -> Result<(), SupersError> {
+    Credentials::resolve(p)?;
+    resolve_umask(p)?;
+    validate_rlimits(p)?;
+    if let Some(s) = p
+        .restart_on_signals
+        .iter()
+        .chain(&p.allowed_signals)
+        .chain(&p.denied_signals)
+        .find(|s| parse_signal(s).is_none())
+    {
+        return Err(SupersError::ProgramExecConfigError(
+            p.name.clone(),
+            format!("unknown signal {s}"),
+        ));
+    }
+    Ok(())
+}
+
+// Convert a resource limit value for `setrlimit`.
+fn rlim(value: RlimitValue) -> libc::rlim_t {
+    match value {
+        RlimitValue::Limited(n) => n as libc::rlim_t,
+        RlimitValue::Unlimited => libc::RLIM_INFINITY,
+    }
+}
+
+// Resolve the resource limits, `rlimits`, for `setrlimit`.
+fn resolve_rlimits(rlimits: &Rlimits) -> Vec<(Resource, libc::rlimit)> {
+    resource_limits(rlimits)
+        .into_iter()
+        .map(|(_, resource, l)| {
+            let limit = libc::rlimit {
+                rlim_cur: rlim(l.soft),
+                rlim_max: rlim(l.hard),
+            };
+            (resource, limit)
+        })
+        .collect()
+}
+
+// Unblock all signals in the calling thread, undoing the mask of init mode.
+// `Command` does not reset the signal mask, so without this, children and
+// re-executed instances of supers would start with those signals blocked.
+fn unblock_signals() -> io::Result<()> {
+    unsafe {
+        let mut set = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        match libc::pthread_sigmask(libc::SIG_SETMASK, &set, ptr::null_mut()) {
+            0 => Ok(()),
+            e => Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+}
+
+/// Configure `cmd` with the working directory, credentials, umask and
+/// resource limits of program `p`. All but the working directory are applied
+/// in the child right before `exec`, the resource limits first, so that
+/// raising a hard limit does not need the privileges the credentials drop.
+#[instrument(level = "debug", skip_all, fields(program = p.name))]
+pub fn configure_command(
+    cmd: &mut Command,
+    p: &ProgramConfig,
+) -> Result<(), SupersError> {
+    if let Some(cwd) = &p.cwd {
+        cmd.current_dir(cwd);
+    }
+    let creds = Credentials::resolve(p)?;
+    let umask = resolve_umask(p)?;
+    let limits = resolve_rlimits(&p.rlimits);
+    debug!(?creds, ?umask, "applying execution settings");
+    unsafe {
+        cmd.pre_exec(move || {
+            unblock_signals()?;
+            for (resource, limit) in &limits {
+                if libc::setrlimit(*resource, limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            creds.apply()?;
+            if let Some(mask) = umask {
+                libc::umask(mask as libc::mode_t);
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+// Handover
+// ========
+
+/// Start time of process `pid`, in clock ticks after boot.
+pub fn start_ticks(pid: u32) -> io::Result<u64> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat"))?;
+    // The name of the process, in parentheses, may contain spaces, so count
+    // the fields after it. The start time is the 22nd field; the state,
+    // right after the name, is the 3rd.
+    stat.rsplit_once(')')
+        .and_then(|(_, fields)| fields.split_whitespace().nth(22 - 3))
+        .and_then(|ticks| ticks.parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "malformed stat file")
+        })
+}
+
+/// A child spawned by the instance of supers that re-executed itself into
+/// this one. The re-execution keeps the pid of supers, so the child is still
+/// a child of supers and can be waited for.
+#[derive(Debug)]
+pub struct AdoptedChild {
+    pid: u32,
+    status: Option<ExitStatus>,
+}
+
+impl AdoptedChild {
+    /// Adopt child `pid`, checking that it is the process that started at
+    /// `start_ticks` rather than one that reused its pid.
+    pub fn adopt(pid: u32, start_ticks: u64) -> io::Result<Self> {
+        if self::start_ticks(pid)? != start_ticks {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("pid {pid} was reused by another process"),
+            ));
+        }
+        let mut child = Self { pid, status: None };
+        // Fails if the process is not a child of supers.
+        child.try_wait()?;
+        Ok(child)
+    }
+
+    pub fn id(&self) -> u32 {
+        self.pid
+    }
+
+    fn wait_with(&mut self, options: i32) -> io::Result<Option<ExitStatus>> {
+        while self.status.is_none() {
+            let mut status = 0;
+            let pid = self.pid as libc::pid_t;
+            match unsafe { libc::waitpid(pid, &mut status, options) } {
+                0 => break,
+                -1 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+                _ => self.status = Some(ExitStatus::from_raw(status)),
+            }
+        }
+        Ok(self.status)
+    }
+
+    /// The exit status of the child, if it exited. Does not block.
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        self.wait_with(libc::WNOHANG)
+    }
+
+    /// Wait for the child to exit.
+    pub fn wait(&mut self) -> io::Result<ExitStatus> {
+        self.wait_with(0)
+            .map(|status| status.expect("child exited"))
+    }
+
+    /// Kill the child, if it is still running.
+    pub fn kill(&mut self) -> io::Result<()> {
+        match self.status {
+            Some(_) => Ok(()),
+            None => send_signal(self.pid, libc::SIGKILL),
+        }
+    }
+}
+
+/// Replace supers with a new instance of its executable, run with the same
+/// arguments and environment. The children of supers, and its pid, survive.
+/// Return only if the execution fails.
+pub fn reexec() -> io::Error {
+    let mut args = env::args_os();
+    let Some(program) = args.next() else {
+        return io::Error::new(io::ErrorKind::NotFound, "no program name");
+    };
+    debug!(?program, "re-executing supers");
+    let mut cmd = Command::new(program);
+    cmd.args(args);
+    unsafe {
+        cmd.pre_exec(unblock_signals);
+    }
+    cmd.exec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        configure_command, parse_signal, parse_umask, start_ticks, validate,
+        AdoptedChild, Credentials,
+    };
+    use crate::{
+        config::{ProgramConfig, Rlimit, RlimitValue, Rlimits},
+        init::block_signals,
+        programs::start_child_program,
+    };
+    use anyhow::Result;
+    use std::{
+        fs,
+        os::unix::{fs::PermissionsExt, process::ExitStatusExt},
+        process::Command,
+        thread,
+    };
+
+    #[test]
+    fn test_parse_umask() {
+        assert_eq!(parse_umask("022"), Some(0o022));
+        assert_eq!(parse_umask("0o027"), Some(0o027));
+        assert_eq!(parse_umask("999"), None);
+        assert_eq!(parse_umask("1777"), None);
+    }
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("SIGKILL"), Some(libc::SIGKILL));
+        assert_eq!(parse_signal("term"), Some(libc::SIGTERM));
+        assert_eq!(parse_signal("10"), Some(10));
+        assert_eq!(parse_signal("SIGFOO"), None);
+        assert_eq!(parse_signal("0"), None);
+    }
+
+    #[test]
+    fn test_resolve_credentials() -> Result<()> {
+        let p = ProgramConfig::default();
+        assert_eq!(Credentials::resolve(&p)?, Credentials::default());
+
+        let p = ProgramConfig {
+            user: Some("0".into()),
+            ..Default::default()
+        };
+        let is_root = unsafe { libc::geteuid() } == 0;
+        let c = Credentials::resolve(&p);
+        assert_eq!(c.is_ok(), is_root);
+        if is_root {
+            let c = c?;
+            assert_eq!(c.uid, Some(0));
+            assert_eq!(c.gid, Some(0));
+            assert_eq!(c.groups, Some(vec![]));
+        }
+
+        let p = ProgramConfig {
+            user: Some("no-such-user-for-supers".into()),
+            ..Default::default()
+        };
+        assert!(Credentials::resolve(&p).is_err());
+
+        // Membership in a group of programs is no credential
+        let p = ProgramConfig {
+            group: Some("web".into()),
+            ..Default::default()
+        };
+        assert_eq!(Credentials::resolve(&p)?, Credentials::default());
+        let p = ProgramConfig {
+            run_as_group: Some("no-such-group-for-supers".into()),
+            ..Default::default()
+        };
+        assert!(Credentials::resolve(&p).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cwd_and_umask() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let p = ProgramConfig {
+            name: "touch".into(),
+            cmd: "touch".into(),
+            args: vec!["created".into()],
+            cwd: Some(temp_dir.path().into()),
+            umask: Some("077".into()),
+            ..Default::default()
+        };
+        let status = start_child_program(&p)?.wait()?;
+        assert!(status.success());
+        let meta = fs::metadata(temp_dir.path().join("created"))?;
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unblock_signals() -> Result<()> {
+        // Block the signals of init mode in a thread of its own, not in the
+        // test harness.
+        let status = thread::spawn(|| -> Result<String> {
+            block_signals()?;
+            let mut cmd = Command::new("cat");
+            cmd.arg("/proc/self/status");
+            configure_command(&mut cmd, &ProgramConfig::default())?;
+            Ok(String::from_utf8(cmd.output()?.stdout)?)
+        })
+        .join()
+        .unwrap()?;
+        assert!(status.contains("SigBlk:\t0000000000000000\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rlimits() -> Result<()> {
+        let rlimits: Rlimits = toml::from_str(
+            r#"
+            nofile = { soft = 123, hard = 456 }
+            fsize = { soft = "unlimited", hard = "unlimited" }
+            "#,
+        )?;
+        assert_eq!(rlimits.fsize.map(|l| l.hard), Some(RlimitValue::Unlimited));
+        assert!(
+            toml::from_str::<Rlimits>("core = { soft = \"x\", hard = 1 }")
+                .is_err()
+        );
+        let p = ProgramConfig {
+            rlimits,
+            ..Default::default()
+        };
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(["-c", "ulimit -Sn; ulimit -Hn; ulimit -f"]);
+        configure_command(&mut cmd, &p)?;
+        let out = cmd.output()?;
+        assert!(out.status.success());
+        assert_eq!(String::from_utf8(out.stdout)?, "123\n456\nunlimited\n");
+
+        let p = ProgramConfig {
+            rlimits: Rlimits {
+                core: Some(Rlimit {
+                    soft: RlimitValue::Unlimited,
+                    hard: RlimitValue::Limited(1),
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let e = validate(&p).unwrap_err().to_string();
+        assert!(e.contains("soft limit unlimited exceeds hard limit 1"));
+        assert!(e.contains("resource core"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_adopted_child() -> Result<()> {
+        let child = Command::new("sleep").arg("10").spawn()?;
+        let pid = child.id();
+        let ticks = start_ticks(pid)?;
+        drop(child);
+        assert!(AdoptedChild::adopt(pid, ticks + 1).is_err());
+        // Only children of supers can be adopted
+        assert!(AdoptedChild::adopt(1, start_ticks(1)?).is_err());
+
+        let mut adopted = AdoptedChild::adopt(pid, ticks)?;
+        assert_eq!(adopted.try_wait()?, None);
+        adopted.kill()?;
+        assert_eq!(adopted.wait()?.signal(), Some(libc::SIGKILL));
+        assert!(adopted.try_wait()?.is_some());
+        adopted.kill()?;
+        Ok(())
+    }
+}
@@ -1,37 +1,199 @@
 use core::time;
 use std::{
     collections::HashMap,
-    process::{Child, Command, ExitStatus},
+    fmt,
+    io::{self, Write},
+    os::unix::{
+        io::AsRawFd,
+        process::{CommandExt, ExitStatusExt},
+    },
+    panic::{self, AssertUnwindSafe},
+    process::{Child, ChildStdin, Command, ExitStatus, Stdio},
     sync::{Arc, Mutex},
     thread::{self, JoinHandle},
+    time::{Instant, SystemTime},
 };
 
 use crossbeam::channel::{unbounded, Receiver, Sender};
-use tracing::{debug, debug_span, instrument, warn};
+use tracing::{debug, debug_span, error, info, instrument, warn};
 
 use crate::{
+    cgroup::Cgroup,
+    config::{Concurrency, KillMode, ProgramKind, StdinMode},
+    env,
     errors::SupersError,
-    messages::CommandMsg,
-    state::{ApplicationState, ProgramStatus},
-    ProgramConfig, RestartPolicy,
+    events::{Event, EventKind},
+    hooks, init,
+    listeners::Listener,
+    messages::{CommandMsg, CommandReply, CommandRequest},
+    process::{self, AdoptedChild},
+    schedule::Schedule,
+    state::{
+        ApplicationState, ApplicationStatus, EndReason, ExitReason,
+        HandoverChild, ProgramState, ProgramStatus,
+    },
+    watch, ProgramConfig, RestartPolicy,
 };
 
-type SupersChild = Option<Child>;
+/// A child of a program, spawned by this instance of supers or adopted from
+/// the instance that handed it over.
+#[derive(Debug)]
+enum ChildProcess {
+    Spawned(Child),
+    Adopted(AdoptedChild),
+}
+
+impl ChildProcess {
+    fn id(&self) -> u32 {
+        match self {
+            ChildProcess::Spawned(c) => c.id(),
+            ChildProcess::Adopted(c) => c.id(),
+        }
+    }
+
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        match self {
+            ChildProcess::Spawned(c) => c.try_wait(),
+            ChildProcess::Adopted(c) => c.try_wait(),
+        }
+    }
+
+    fn wait(&mut self) -> io::Result<ExitStatus> {
+        match self {
+            ChildProcess::Spawned(c) => c.wait(),
+            ChildProcess::Adopted(c) => c.wait(),
+        }
+    }
+
+    fn kill(&mut self) -> io::Result<()> {
+        match self {
+            ChildProcess::Spawned(c) => c.kill(),
+            ChildProcess::Adopted(c) => c.kill(),
+        }
+    }
+
+    // The stdin pipe of the child, if any. Pipes do not survive a handover,
+    // so adopted children have none.
+    fn stdin(&mut self) -> Option<&mut ChildStdin> {
+        match self {
+            ChildProcess::Spawned(c) => c.stdin.as_mut(),
+            ChildProcess::Adopted(_) => None,
+        }
+    }
+
+    fn has_pipes(&self) -> bool {
+        match self {
+            ChildProcess::Spawned(c) => c.stdin.is_some() || c.stdout.is_some(),
+            ChildProcess::Adopted(_) => false,
+        }
+    }
+}
+
+impl Drop for ChildProcess {
+    fn drop(&mut self) {
+        // Nobody waits for the child anymore, so the reaper may reap it.
+        init::release(self.id());
+    }
+}
+
+impl From<Child> for ChildProcess {
+    fn from(child: Child) -> Self {
+        ChildProcess::Spawned(child)
+    }
+}
+
+type SupersChild = Option<ChildProcess>;
 
 // Amount of time the command thread will wait for a command message on the command channel.
 pub const WAIT_TIMEOUT: time::Duration = time::Duration::from_millis(10);
+// Amount of time a program thread that handed over its child waits for supers
+// to re-execute itself before resuming.
+pub const HANDOVER_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+// Maximum amount of input waiting for room in the stdin pipe of a child.
+const STDIN_BUFFER_SIZE: usize = 1 << 20;
+
+/// Build a command running `program` with `args` in the environment, working
+/// directory and credentials of program `p`.
+pub fn build_command(
+    p: &ProgramConfig,
+    program: &str,
+    args: &[String],
+) -> Result<Command, SupersError> {
+    let mut cmd = Command::new(program);
+    set_up_command(&mut cmd, p, args)?;
+    Ok(cmd)
+}
+
+// Give `cmd` the arguments `args` and the environment, working directory and
+// credentials of program `p`.
+fn set_up_command(
+    cmd: &mut Command,
+    p: &ProgramConfig,
+    args: &[String],
+) -> Result<(), SupersError> {
+    let secrets = env::read_secrets(p)?;
+    // Variables that are not UTF-8 are inherited, but cannot be referred to.
+    let supers_env = std::env::vars_os()
+        .filter_map(|(k, v)| {
+            Some((k.into_string().ok()?, v.into_string().ok()?))
+        })
+        .collect();
+    if !p.inherit_env {
+        cmd.env_clear();
+    }
+    cmd.args(args)
+        .envs(env::resolve_env(p, &supers_env)?)
+        .envs(secrets.iter().map(|(k, v)| (k, v.expose())));
+    process::configure_command(cmd, p)
+}
 
 /// Function to start a program with config given by, `p`, in a child process.
-#[instrument(level = "debug")]
+#[instrument(level = "debug", skip_all, fields(program = p.name, cmd = p.cmd))]
 pub fn start_child_program(p: &ProgramConfig) -> Result<Child, SupersError> {
-    debug!("spawning child");
-    Command::new(&p.cmd)
-        .args(&p.args)
-        .envs(&p.env)
-        .spawn()
-        .map_err(|e| {
+    debug!(args = ?p.args, "spawning child");
+    let mut cmd = Command::new(&p.cmd);
+    // The child joins the cgroup before it drops the privileges of supers,
+    // which the write to `cgroup.procs` may need.
+    if let Some(cgroup) = Cgroup::of(p) {
+        cgroup
+            .prepare(p)
+            .and_then(|_| cgroup.attach(&mut cmd))
+            .map_err(|e| {
+                SupersError::ProgramCgroupError(p.name.to_string(), e)
+            })?;
+    }
+    set_up_command(&mut cmd, p, &p.args)?;
+    match p.stdin {
+        // Listeners read events on their stdin and answer on their stdout.
+        _ if p.listener.is_some() => {
+            cmd.stdin(Stdio::piped()).stdout(Stdio::piped());
+        }
+        StdinMode::Inherit => {}
+        StdinMode::Null => {
+            cmd.stdin(Stdio::null());
+        }
+        StdinMode::Pipe => {
+            cmd.stdin(Stdio::piped());
+        }
+    }
+    if p.kill_mode == KillMode::ProcessGroup {
+        cmd.process_group(0);
+    }
+    let child = init::spawn(&mut cmd).map_err(|e| {
+        SupersError::ProgramProcessSpawnError(p.name.to_string(), e)
+    })?;
+    // A child that does not read its stdin, or write its stdout, must not
+    // block the program thread.
+    let fds = [
+        child.stdin.as_ref().map(|s| s.as_raw_fd()),
+        child.stdout.as_ref().map(|s| s.as_raw_fd()),
+    ];
+    for fd in fds.into_iter().flatten() {
+        process::set_nonblocking(fd).map_err(|e| {
             SupersError::ProgramProcessSpawnError(p.name.to_string(), e)
-        })
+        })?;
+    }
+    Ok(child)
 }
 
 /// Update the status of program with name, `pgm_name`, to status, `status`.
@@ -44,41 +206,656 @@ pub fn update_pgm_status(
 ) {
     debug!("updating program status");
     let mut a = app_state.lock().unwrap();
-    *a.programs.entry(pgm_name.into()).or_insert(status) = status;
+    let state = a.programs.entry(pgm_name.into()).or_default();
+    // A program leaving the fatal status recovered from its error.
+    if status != ProgramStatus::Fatal {
+        state.fatal_error = None;
+    }
+    state.status = status;
+}
+
+/// Update the next scheduled run of program `pgm_name` to `next_run`.
+/// This function panics if it cannot lock the app_state object.
+#[instrument(level = "debug", skip(app_state))]
+pub fn update_pgm_next_run(
+    app_state: Arc<Mutex<ApplicationState>>,
+    pgm_name: &str,
+    next_run: Option<SystemTime>,
+) {
+    debug!("updating next run");
+    let mut a = app_state.lock().unwrap();
+    a.programs.entry(pgm_name.into()).or_default().next_run = next_run;
+}
+
+/// Count a new child spawned for program `pgm_name`.
+/// This function panics if it cannot lock the app_state object.
+#[instrument(level = "debug", skip(app_state))]
+pub fn update_pgm_starts(
+    app_state: Arc<Mutex<ApplicationState>>,
+    pgm_name: &str,
+) {
+    debug!("counting program start");
+    let mut a = app_state.lock().unwrap();
+    a.programs.entry(pgm_name.into()).or_default().starts += 1;
+}
+
+/// Record the reason, `reason`, why the child of program `pgm_name` exited.
+/// This function panics if it cannot lock the app_state object.
+#[instrument(level = "debug", skip(app_state))]
+pub fn update_pgm_exit(
+    app_state: Arc<Mutex<ApplicationState>>,
+    pgm_name: &str,
+    reason: ExitReason,
+) {
+    debug!("recording program exit");
+    let mut a = app_state.lock().unwrap();
+    let state = a.programs.entry(pgm_name.into()).or_default();
+    state.exit_code = match reason {
+        ExitReason::Exited(status) => status.code(),
+        ExitReason::OomKilled => None,
+    };
+    state.last_exit = Some(reason);
+}
+
+/// Mark program `pgm_name` as `Fatal` because of `reason`, or clear its fatal
+/// error and mark it as stopped when `reason` is `None`.
+/// This function panics if it cannot lock the app_state object.
+#[instrument(level = "debug", skip(app_state))]
+pub fn update_pgm_fatal(
+    app_state: Arc<Mutex<ApplicationState>>,
+    pgm_name: &str,
+    reason: Option<String>,
+) {
+    debug!("updating program fatal error");
+    let mut a = app_state.lock().unwrap();
+    let state = a.programs.entry(pgm_name.into()).or_default();
+    state.status = match reason {
+        Some(_) => ProgramStatus::Fatal,
+        None => ProgramStatus::Stopped,
+    };
+    state.fatal_error = reason;
+}
+
+/// Record whether program `pgm_name` was last stopped by an operator.
+/// This function panics if it cannot lock the app_state object.
+#[instrument(level = "debug", skip(app_state))]
+pub fn update_pgm_stopped_by_operator(
+    app_state: &Arc<Mutex<ApplicationState>>,
+    pgm_name: &str,
+    stopped: bool,
+) {
+    debug!("updating operator stop");
+    let mut a = app_state.lock().unwrap();
+    a.programs
+        .entry(pgm_name.into())
+        .or_default()
+        .stopped_by_operator = stopped;
+}
+
+/// Record whether program `pgm_name` finished, not to run again without a
+/// command.
+/// This function panics if it cannot lock the app_state object.
+#[instrument(level = "debug", skip(app_state))]
+pub fn update_pgm_finished(
+    app_state: &Arc<Mutex<ApplicationState>>,
+    pgm_name: &str,
+    finished: bool,
+) {
+    debug!("updating program finished");
+    let mut a = app_state.lock().unwrap();
+    a.programs.entry(pgm_name.into()).or_default().finished = finished;
+}
+
+/// Record the child of program `pgm_name` handed over to the next instance of
+/// supers, if any.
+/// This function panics if it cannot lock the app_state object.
+#[instrument(level = "debug", skip(app_state))]
+pub fn update_pgm_handover(
+    app_state: &Arc<Mutex<ApplicationState>>,
+    pgm_name: &str,
+    handover: Option<HandoverChild>,
+) {
+    debug!("updating handed over child");
+    let mut a = app_state.lock().unwrap();
+    a.programs.entry(pgm_name.into()).or_default().handover = handover;
+}
+
+/// Update the run history of program `pgm_name` with `update`.
+/// This function panics if it cannot lock the app_state object.
+#[instrument(level = "debug", skip(app_state, update))]
+pub fn update_pgm_history(
+    app_state: &Arc<Mutex<ApplicationState>>,
+    pgm_name: &str,
+    update: impl FnOnce(&mut ProgramState),
+) {
+    debug!("updating program history");
+    let mut a = app_state.lock().unwrap();
+    update(a.programs.entry(pgm_name.into()).or_default());
+}
+
+/// Publish event `kind` of program `pgm_name` on the event bus.
+/// This function panics if it cannot lock the app_state object.
+#[instrument(level = "debug", skip(app_state))]
+pub fn publish_pgm_event(
+    app_state: &Arc<Mutex<ApplicationState>>,
+    pgm_name: &str,
+    kind: EventKind,
+) {
+    debug!("publishing program event");
+    app_state.lock().unwrap().events.publish(pgm_name, kind);
+}
+
+// Return `true` if all the programs that program `p` depends on completed.
+fn dependencies_completed(
+    app_state: &Arc<Mutex<ApplicationState>>,
+    p: &ProgramConfig,
+) -> bool {
+    let a = app_state.lock().unwrap();
+    p.depends_on.iter().all(|dep| {
+        a.programs
+            .get(dep)
+            .is_some_and(|s| s.status == ProgramStatus::Completed)
+    })
+}
+
+// Return `true` if program `name` is `Fatal`, e.g. after a failed spawn.
+fn program_fatal(app_state: &Arc<Mutex<ApplicationState>>, name: &str) -> bool {
+    let a = app_state.lock().unwrap();
+    a.programs
+        .get(name)
+        .is_some_and(|s| s.status == ProgramStatus::Fatal)
+}
+
+// Return `true` if the application is stopping, so programs must not start.
+fn application_stopping(app_state: &Arc<Mutex<ApplicationState>>) -> bool {
+    let a = app_state.lock().unwrap();
+    matches!(a.application_status, ApplicationStatus::Stopping)
+}
+
+/// Mutable state of the thread supervising a program.
+#[derive(Debug, Default)]
+struct ProgramRuntime {
+    child: SupersChild,
+    // Number of consecutive failures of the program, for the restart backoff.
+    failures: u32,
+    // When to restart the program, if a delayed restart is pending.
+    restart_at: Option<Instant>,
+    // Schedule of the program, if it runs periodically.
+    schedule: Option<Schedule>,
+    // Next scheduled run, if any.
+    next_run: Option<SystemTime>,
+    // Scheduled runs waiting for the current run to finish.
+    queued_runs: u32,
+    // Whether a `Stop` command paused the schedule.
+    schedule_paused: bool,
+    // Whether a start waits for the dependencies of the program to complete.
+    waiting_for_deps: bool,
+    // Why the last attempt to spawn a child failed, if it did.
+    spawn_error: Option<String>,
+    // Why the last input was not delivered to the child, if it was not.
+    stdin_error: Option<String>,
+    // Input waiting for room in the stdin pipe of the child.
+    stdin_pending: Vec<u8>,
+    // OOM kills in the cgroup of the program when the child was spawned.
+    oom_kills: u64,
+    // Feeds the events to the child, if the program is an event listener.
+    listener: Option<Listener>,
 }
 
-#[derive(Debug, PartialEq)]
+impl ProgramRuntime {
+    fn new(program_config: &ProgramConfig) -> Self {
+        let schedule = program_config
+            .schedule
+            .as_deref()
+            .and_then(|s| Schedule::parse(s).ok());
+        let next_run = schedule
+            .as_ref()
+            .and_then(|s| s.next_after(SystemTime::now()));
+        ProgramRuntime {
+            schedule,
+            next_run,
+            ..Default::default()
+        }
+    }
+
+    // Write the input pending for the child, as far as its stdin pipe has
+    // room. Without a pipe to write to, the input is dropped.
+    fn flush_stdin(&mut self) {
+        if self.stdin_pending.is_empty() {
+            return;
+        }
+        match self.child.as_mut().and_then(|c| c.stdin()) {
+            Some(stdin) => write_stdin(stdin, &mut self.stdin_pending),
+            None => self.stdin_pending.clear(),
+        }
+    }
+
+    // Get the command to process in this step of the state machine.
+    // A command from the channel cancels a pending restart; otherwise, due
+    // restarts and scheduled runs turn into `Start` commands.
+    fn next_command(
+        &mut self,
+        msg: Option<CommandMsg>,
+        status: &ChildStatus,
+        concurrency: Concurrency,
+    ) -> Option<CommandMsg> {
+        if let Some(msg) = msg {
+            // Signals and input leave the lifecycle of the program untouched.
+            if msg.is_lifecycle() {
+                self.restart_at = None;
+                self.waiting_for_deps = false;
+                self.schedule_paused = msg == CommandMsg::Stop;
+            }
+            return Some(msg);
+        }
+        if self.restart_at.is_some_and(|at| Instant::now() >= at) {
+            self.restart_at = None;
+            return Some(CommandMsg::Start);
+        }
+        self.next_scheduled_run(status, concurrency)
+    }
+
+    // The outcome of the command `msg`, processed when the child had `status`.
+    fn reply_to(&self, msg: &CommandMsg, status: &ChildStatus) -> CommandReply {
+        let alive = matches!(status, ChildStatus::Alive);
+        match msg {
+            CommandMsg::Start if alive => CommandReply::AlreadyRunning,
+            CommandMsg::Restart if matches!(status, ChildStatus::NoChild) => {
+                CommandReply::NotRunning
+            }
+            CommandMsg::Start | CommandMsg::Restart => {
+                match (&self.child, &self.spawn_error) {
+                    (Some(c), _) => CommandReply::Spawned(c.id()),
+                    (None, Some(e)) => CommandReply::SpawnError(e.clone()),
+                    (None, None) if self.waiting_for_deps => {
+                        CommandReply::WaitingForDependencies
+                    }
+                    (None, None) => {
+                        CommandReply::SpawnError("child not spawned".into())
+                    }
+                }
+            }
+            CommandMsg::Stop if alive => CommandReply::Stopped,
+            CommandMsg::Stdin(_) if alive && self.stdin_error.is_some() => {
+                CommandReply::Rejected(self.stdin_error.clone().unwrap())
+            }
+            CommandMsg::Signal(_)
+            | CommandMsg::SignalAll(_)
+            | CommandMsg::Stdin(_)
+                if alive =>
+            {
+                CommandReply::Delivered
+            }
+            CommandMsg::Stop
+            | CommandMsg::Signal(_)
+            | CommandMsg::SignalAll(_)
+            | CommandMsg::Stdin(_)
+            | CommandMsg::Handover => CommandReply::NotRunning,
+        }
+    }
+
+    // Issue `Start` for a due scheduled run, or for a queued run once the
+    // previous run finished.
+    fn next_scheduled_run(
+        &mut self,
+        status: &ChildStatus,
+        concurrency: Concurrency,
+    ) -> Option<CommandMsg> {
+        let schedule = self.schedule.as_ref()?;
+        if self.schedule_paused {
+            return None;
+        }
+        let now = SystemTime::now();
+        if self.next_run.is_some_and(|t| now >= t) {
+            self.next_run = schedule.next_after(now);
+            match (status, concurrency) {
+                (ChildStatus::Alive, Concurrency::Skip) => {
+                    warn!("previous run still going; skipping scheduled run");
+                }
+                (ChildStatus::Alive, Concurrency::Queue) => {
+                    self.queued_runs += 1;
+                    debug!(queued = self.queued_runs, "queueing scheduled run");
+                }
+                _ => return Some(CommandMsg::Start),
+            }
+        }
+        if self.queued_runs > 0 && matches!(status, ChildStatus::NoChild) {
+            self.queued_runs -= 1;
+            return Some(CommandMsg::Start);
+        }
+        None
+    }
+}
+
+#[derive(PartialEq)]
 enum Action {
     ResetChild,
     SpawnChild,
     KillChild,
+    SignalChild(i32),
+    SignalAll(i32),
+    WriteStdin(Vec<u8>),
     ApplyPolicy(ExitStatus),
+    RecordExit(ExitStatus),
     UpdateStatus(ProgramStatus),
 }
 
+// Input for the child is not logged, since it may hold secrets.
+impl fmt::Debug for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::ResetChild => write!(f, "ResetChild"),
+            Action::SpawnChild => write!(f, "SpawnChild"),
+            Action::KillChild => write!(f, "KillChild"),
+            Action::SignalChild(signal) => write!(f, "SignalChild({signal})"),
+            Action::SignalAll(signal) => write!(f, "SignalAll({signal})"),
+            Action::WriteStdin(data) => {
+                write!(f, "WriteStdin({} bytes)", data.len())
+            }
+            Action::ApplyPolicy(code) => write!(f, "ApplyPolicy({code:?})"),
+            Action::RecordExit(code) => write!(f, "RecordExit({code:?})"),
+            Action::UpdateStatus(status) => {
+                write!(f, "UpdateStatus({status:?})")
+            }
+        }
+    }
+}
+
+// Signature of the function running the state machine of a program.
+type RunStateMachine = fn(
+    &ProgramConfig,
+    &mut ProgramRuntime,
+    Arc<Mutex<ApplicationState>>,
+    &Sender<CommandRequest>,
+    &Receiver<CommandRequest>,
+) -> Result<(), SupersError>;
+
+// Run the state machine of program `program_config` with `run` and supervise
+// it: when `run` fails or panics, kill the child, mark the program as `Fatal`
+// with the reason and wait for a `Start` or `Restart` command to run it again.
+#[instrument(level = "debug", skip_all, fields(program = program_config.name))]
+fn supervise(
+    program_config: &ProgramConfig,
+    app_state: Arc<Mutex<ApplicationState>>,
+    cmd_tx: Sender<CommandRequest>,
+    cmd_rx: Receiver<CommandRequest>,
+    events: Option<Receiver<Event>>,
+    run: RunStateMachine,
+) -> Result<(), SupersError> {
+    loop {
+        let mut runtime = ProgramRuntime::new(program_config);
+        runtime.listener = program_config
+            .listener
+            .clone()
+            .zip(events.clone())
+            .map(|(config, events)| Listener::new(config, events));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            run(
+                program_config,
+                &mut runtime,
+                app_state.clone(),
+                &cmd_tx,
+                &cmd_rx,
+            )
+        }));
+        let reason = match result {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) => e.to_string(),
+            Err(payload) => match payload.downcast::<String>() {
+                Ok(msg) => format!("panicked: {}", msg),
+                Err(payload) => match payload.downcast::<&str>() {
+                    Ok(msg) => format!("panicked: {}", msg),
+                    Err(_) => "panicked".into(),
+                },
+            },
+        };
+        error!(reason, "program thread failed");
+        if let Some(mut child) = runtime.child.take() {
+            warn!(pid = child.id(), "killing unsupervised child");
+            match program_config.kill_mode {
+                KillMode::ProcessGroup => {
+                    process::kill_process_group(child.id())
+                }
+                KillMode::Process => child.kill(),
+            }
+            .and_then(|_| child.wait())
+            .map_or_else(|e| warn!(error = ?e, "cannot kill child"), |_| ());
+        }
+        publish_pgm_event(
+            &app_state,
+            &program_config.name,
+            EventKind::Fatal {
+                error: reason.clone(),
+            },
+        );
+        update_pgm_fatal(app_state.clone(), &program_config.name, Some(reason));
+        // Wait for a command to run the program again, then hand the command
+        // over to the new state machine.
+        loop {
+            let Ok(request) = cmd_rx.recv() else {
+                return Ok(());
+            };
+            if matches!(request.msg, CommandMsg::Start | CommandMsg::Restart) {
+                update_pgm_fatal(app_state.clone(), &program_config.name, None);
+                // The new state machine has no child to restart.
+                let msg = CommandMsg::Start;
+                cmd_tx.send(CommandRequest { msg, ..request })?;
+                break;
+            }
+            if let Some(reply) = request.reply {
+                let _ = reply.send(CommandReply::NotRunning);
+            }
+        }
+    }
+}
+
 #[instrument(level = "debug", skip_all, fields(program = program_config.name))]
 fn run_state_machine_with_effects(
     program_config: &ProgramConfig,
+    runtime: &mut ProgramRuntime,
     app_state: Arc<Mutex<ApplicationState>>,
-    cmd_tx: Sender<CommandMsg>,
-    cmd_rx: Receiver<CommandMsg>,
+    cmd_tx: &Sender<CommandRequest>,
+    cmd_rx: &Receiver<CommandRequest>,
 ) -> Result<(), SupersError> {
     debug!("run full state machine");
-    let mut current_child: SupersChild = None;
+    if runtime.schedule.is_some() {
+        update_pgm_next_run(
+            app_state.clone(),
+            &program_config.name,
+            runtime.next_run,
+        );
+    }
+    adopt_child(program_config, runtime, &app_state);
     loop {
-        let msg = cmd_rx.recv_timeout(WAIT_TIMEOUT).ok();
+        let (msg, reply) = match cmd_rx.recv_timeout(WAIT_TIMEOUT) {
+            Ok(request) => (Some(request.msg), request.reply),
+            Err(_) => (None, None),
+        };
+        if msg == Some(CommandMsg::Handover) {
+            let outcome =
+                hand_over(program_config, runtime, cmd_tx, app_state.clone())?;
+            if let Some(reply) = reply {
+                let _ = reply.send(outcome.clone());
+            }
+            let held = wait_for_handover(cmd_rx, &outcome, HANDOVER_TIMEOUT);
+            warn!("supers did not re-execute itself; resuming");
+            update_pgm_handover(&app_state, &program_config.name, None);
+            for request in held {
+                cmd_tx.send(request)?;
+            }
+            continue;
+        }
+        if let Some(msg) = msg.as_ref().filter(|m| m.is_lifecycle()) {
+            update_pgm_stopped_by_operator(
+                &app_state,
+                &program_config.name,
+                *msg == CommandMsg::Stop,
+            );
+        }
+        let status =
+            get_child_status(&program_config.name, &mut runtime.child)?;
+        let next_run = runtime.next_run;
+        let msg = match runtime.next_command(
+            msg,
+            &status,
+            program_config.concurrency,
+        ) {
+            None if runtime.waiting_for_deps
+                && dependencies_completed(&app_state, program_config) =>
+            {
+                runtime.waiting_for_deps = false;
+                Some(CommandMsg::Start)
+            }
+            // Restarting a `Fatal` program without a child starts it again.
+            Some(CommandMsg::Restart)
+                if matches!(status, ChildStatus::NoChild)
+                    && program_fatal(&app_state, &program_config.name) =>
+            {
+                Some(CommandMsg::Start)
+            }
+            msg => msg,
+        };
+        if runtime.next_run != next_run {
+            update_pgm_next_run(
+                app_state.clone(),
+                &program_config.name,
+                runtime.next_run,
+            );
+        }
         let _span = debug_span!("message_span", ?msg).entered();
         debug!("received command message");
-        let status =
-            get_child_status(&program_config.name, &mut current_child)?;
         let actions = state_machine_step(&status, &msg);
+        runtime.spawn_error = None;
+        runtime.stdin_error = None;
         run_actions(
             &actions,
-            &mut current_child,
-            &cmd_tx,
+            runtime,
+            cmd_tx,
             program_config,
             app_state.clone(),
         )?;
+        if let (Some(reply), Some(msg)) = (reply, &msg) {
+            // The requester may have stopped waiting for the reply.
+            let _ = reply.send(runtime.reply_to(msg, &status));
+        }
+        runtime.flush_stdin();
+        if let (Some(listener), Some(ChildProcess::Spawned(child))) =
+            (&mut runtime.listener, &mut runtime.child)
+        {
+            if let Err(e) = listener.poll(child) {
+                warn!(error = e, "restarting event listener");
+                cmd_tx.send(CommandMsg::Restart.into())?;
+            }
+        }
+    }
+}
+
+// Number of OOM kills in the cgroup of program `p` so far, if it has one.
+fn oom_kills(p: &ProgramConfig) -> u64 {
+    Cgroup::of(p).and_then(|c| c.oom_kills().ok()).unwrap_or(0)
+}
+
+// Adopt the child that the previous instance of supers handed over to the
+// program, if any. A child that cannot be adopted is lost.
+fn adopt_child(
+    program_config: &ProgramConfig,
+    runtime: &mut ProgramRuntime,
+    app_state: &Arc<Mutex<ApplicationState>>,
+) {
+    let name = &program_config.name;
+    let handover = app_state
+        .lock()
+        .unwrap()
+        .programs
+        .get_mut(name)
+        .and_then(|s| s.handover.take());
+    let Some(HandoverChild { pid, start_ticks }) = handover else {
+        return;
+    };
+    match AdoptedChild::adopt(pid, start_ticks) {
+        Ok(child) => {
+            info!(pid, "adopted child");
+            runtime.child = Some(ChildProcess::Adopted(child));
+            runtime.oom_kills = oom_kills(program_config);
+            update_pgm_status(app_state.clone(), name, ProgramStatus::Running);
+        }
+        Err(e) => {
+            warn!(pid, error = ?e, "cannot adopt child");
+            update_pgm_history(app_state, name, |s| s.record_lost());
+        }
+    }
+}
+
+// Hand the child of the program over to the next instance of supers, by
+// recording its pid for the next instance to adopt. Children with pipes to
+// supers are stopped instead, since the pipes do not survive the handover.
+fn hand_over(
+    program_config: &ProgramConfig,
+    runtime: &mut ProgramRuntime,
+    tx: &Sender<CommandRequest>,
+    app_state: Arc<Mutex<ApplicationState>>,
+) -> Result<CommandReply, SupersError> {
+    let name = &program_config.name;
+    let status = get_child_status(name, &mut runtime.child)?;
+    let Some(child) = runtime.child.as_ref() else {
+        return Ok(CommandReply::NotRunning);
+    };
+    if !matches!(status, ChildStatus::Alive) {
+        // The child exited and was reaped, so there is nothing to adopt.
+        return Ok(CommandReply::NotRunning);
+    }
+    if child.has_pipes() {
+        info!(
+            pid = child.id(),
+            "stopping child with pipes before handover"
+        );
+        let actions = [
+            Action::KillChild,
+            Action::UpdateStatus(ProgramStatus::Stopped),
+            Action::ResetChild,
+        ];
+        run_actions(&actions, runtime, tx, program_config, app_state)?;
+        return Ok(CommandReply::Stopped);
+    }
+    let pid = child.id();
+    let start_ticks = process::start_ticks(pid).map_err(|e| {
+        SupersError::ProgramCheckProcessStatusError(name.into(), e)
+    })?;
+    info!(pid, "handing over child");
+    update_pgm_handover(
+        &app_state,
+        name,
+        Some(HandoverChild { pid, start_ticks }),
+    );
+    Ok(CommandReply::HandedOver(pid))
+}
+
+// Wait for supers to re-execute itself after the program handed over its
+// child with `outcome`, until `timeout` expires without a handover request.
+// Other commands could replace the child handed over, so they are held back
+// and returned, to be processed if supers does not re-execute itself.
+fn wait_for_handover(
+    cmd_rx: &Receiver<CommandRequest>,
+    outcome: &CommandReply,
+    timeout: time::Duration,
+) -> Vec<CommandRequest> {
+    let mut held = vec![];
+    let mut until = Instant::now() + timeout;
+    loop {
+        let wait = until.saturating_duration_since(Instant::now());
+        match cmd_rx.recv_timeout(wait) {
+            Ok(request) if request.msg == CommandMsg::Handover => {
+                if let Some(reply) = request.reply {
+                    let _ = reply.send(outcome.clone());
+                }
+                until = Instant::now() + timeout;
+            }
+            Ok(request) => {
+                debug!(msg = ?request.msg, "holding back command during handover");
+                held.push(request);
+            }
+            Err(_) => return held,
+        }
     }
 }
 
@@ -105,12 +882,25 @@ fn state_machine_step(
                 Action::UpdateStatus(ProgramStatus::Running),
             ]
         }
+        (
+            ChildStatus::NoChild,
+            Some(
+                CommandMsg::Signal(_)
+                | CommandMsg::SignalAll(_)
+                | CommandMsg::Stdin(_)
+                | CommandMsg::Handover,
+            ),
+        ) => {
+            // There is no child to signal, write to or hand over.
+            vec![]
+        }
         (
             ChildStatus::NoChild,
             Some(CommandMsg::Restart | CommandMsg::Stop),
         ) => {
-            // If we don't have a child, `Stop` does nothing.
-            // TODO: Do we want `Restart` to spawn a child?
+            // If we don't have a child, `Stop` does nothing. Neither does
+            // `Restart`, so that a stopped program stays stopped; the caller
+            // turns the restart of a `Fatal` program into a `Start`.
             vec![]
         }
         (ChildStatus::Alive, None) => {
@@ -131,24 +921,56 @@ fn state_machine_step(
         (ChildStatus::Alive, Some(CommandMsg::Restart)) => {
             vec![Action::KillChild, Action::SpawnChild]
         }
-        (ChildStatus::Exited(code), None) => {
-            // The child exited, and there is no command in the queue.
+        (ChildStatus::Alive, Some(CommandMsg::Signal(signal))) => {
+            // Signals do not change the status of the program.
+            vec![Action::SignalChild(*signal)]
+        }
+        (ChildStatus::Alive, Some(CommandMsg::SignalAll(signal))) => {
+            vec![Action::SignalAll(*signal)]
+        }
+        (ChildStatus::Alive, Some(CommandMsg::Stdin(data))) => {
+            vec![Action::WriteStdin(data.clone())]
+        }
+        (ChildStatus::Alive, Some(CommandMsg::Handover)) => {
+            // The program thread hands the child over before the state
+            // machine runs.
+            vec![]
+        }
+        (
+            ChildStatus::Exited(code),
+            None
+            | Some(
+                CommandMsg::Signal(_)
+                | CommandMsg::SignalAll(_)
+                | CommandMsg::Stdin(_)
+                | CommandMsg::Handover,
+            ),
+        ) => {
+            // The child exited, and there is no command in the queue, or
+            // only a signal or input that cannot be delivered anymore.
             // Let's apply the policies, if any.
             vec![
                 Action::UpdateStatus(ProgramStatus::Stopped),
+                Action::RecordExit(*code),
+                Action::ResetChild,
                 Action::ApplyPolicy(*code),
             ]
         }
-        (ChildStatus::Exited(_), Some(CommandMsg::Stop)) => {
+        (ChildStatus::Exited(code), Some(CommandMsg::Stop)) => {
             // Child has exited, so we ignore the `Stop` command
-            vec![Action::UpdateStatus(ProgramStatus::Stopped)]
+            vec![
+                Action::UpdateStatus(ProgramStatus::Stopped),
+                Action::RecordExit(*code),
+                Action::ResetChild,
+            ]
         }
         (
-            ChildStatus::Exited(_),
+            ChildStatus::Exited(code),
             Some(CommandMsg::Start | CommandMsg::Restart),
         ) => {
-            // Child has exited, so we ignore the `Stop` command
+            // Child has exited, so we spawn a new one right away
             vec![
+                Action::RecordExit(*code),
                 Action::SpawnChild,
                 Action::UpdateStatus(ProgramStatus::Running),
             ]
@@ -159,14 +981,125 @@ fn state_machine_step(
 #[instrument(level = "debug", skip_all, fields(?actions))]
 fn run_actions(
     actions: &[Action],
-    child: &mut SupersChild,
-    tx: &Sender<CommandMsg>,
+    runtime: &mut ProgramRuntime,
+    tx: &Sender<CommandRequest>,
     program_config: &ProgramConfig,
     app_state: Arc<Mutex<ApplicationState>>,
 ) -> Result<(), SupersError> {
     debug!("processing all actions");
     for action in actions {
-        run_action(action, child, tx, program_config, app_state.clone())?;
+        run_action(action, runtime, tx, program_config, app_state.clone())?;
+        if *action == Action::SpawnChild && runtime.child.is_none() {
+            // The dependencies or the `pre_start` hook blocked the spawn.
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Write as much of `pending` as fits in the non-blocking stdin pipe of the
+// child, keeping the rest for later. The input is dropped if the pipe breaks.
+fn write_stdin(stdin: &mut impl Write, pending: &mut Vec<u8>) {
+    let mut written = 0;
+    while written < pending.len() {
+        match stdin.write(&pending[written..]) {
+            Ok(n) => written += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => {
+                let dropped = pending.len() - written;
+                warn!(error = ?e, dropped, "cannot write to stdin of child");
+                pending.clear();
+                return;
+            }
+        }
+    }
+    pending.drain(..written);
+    debug!(written, pending = pending.len(), "wrote to stdin of child");
+}
+
+// Return `true` if the exit of a child with `status` restarts program `p`
+// under the `OnError` policy.
+fn restart_on_error(status: ExitStatus, p: &ProgramConfig) -> bool {
+    match (status.code(), status.signal()) {
+        (Some(c), _) if p.no_restart_on_exit_codes.contains(&c) => false,
+        (Some(c), _) if p.restart_on_exit_codes.contains(&c) => true,
+        (Some(c), _) => c != 0 && p.restart_on_exit_codes.is_empty(),
+        (None, Some(signal)) => {
+            p.restart_on_signals.is_empty()
+                || p.restart_on_signals
+                    .iter()
+                    .any(|s| process::parse_signal(s) == Some(signal))
+        }
+        (None, None) => true,
+    }
+}
+
+// Restart the program according to its policy. `status` is the exit status
+// of the child, or `None` if the program failed before spawning it.
+// Restarts after failures are delayed by the backoff of the program.
+fn apply_policy(
+    status: Option<ExitStatus>,
+    runtime: &mut ProgramRuntime,
+    tx: &Sender<CommandRequest>,
+    program_config: &ProgramConfig,
+    app_state: &Arc<Mutex<ApplicationState>>,
+) -> Result<(), SupersError> {
+    // Exit codes in `no_restart_on_exit_codes` are expected, so they do not
+    // count as failures.
+    let expected = status.is_some_and(|s| {
+        s.code().is_some_and(|c| {
+            program_config.no_restart_on_exit_codes.contains(&c)
+        })
+    });
+    let success = status.is_some_and(|s| s.success());
+    if success || expected {
+        runtime.failures = 0;
+    } else {
+        runtime.failures += 1;
+    }
+    let restart = match program_config.restartpolicy {
+        // Under this policy, we **always** restart, except for successful
+        // runs of scheduled programs, which wait for their next run, of
+        // oneshot programs, which are done, and of expected exit codes
+        RestartPolicy::Always => {
+            !expected
+                && !(success
+                    && (program_config.schedule.is_some()
+                        || program_config.kind == ProgramKind::Oneshot))
+        }
+        // Do nothing, keep in `Exited` state.
+        RestartPolicy::Never => false,
+        // We restart if the program failed, as defined by the exit codes and
+        // signals of the program
+        RestartPolicy::OnError => {
+            status.is_none_or(|s| restart_on_error(s, program_config))
+        }
+    };
+    if !restart {
+        if program_config.schedule.is_none() {
+            update_pgm_finished(app_state, &program_config.name, true);
+        }
+        return Ok(());
+    }
+    if status.is_some() {
+        update_pgm_history(app_state, &program_config.name, |s| {
+            s.record_policy_restart()
+        });
+    }
+    let delay = program_config.backoff.delay(runtime.failures);
+    publish_pgm_event(
+        app_state,
+        &program_config.name,
+        EventKind::RestartScheduled {
+            delay_ms: delay.as_millis(),
+        },
+    );
+    if delay.is_zero() {
+        tx.send(CommandMsg::Start.into())?;
+    } else {
+        debug!(?delay, failures = runtime.failures, "delaying restart");
+        runtime.restart_at = Some(Instant::now() + delay);
     }
     Ok(())
 }
@@ -174,30 +1107,144 @@ fn run_actions(
 #[instrument(level = "debug", skip_all, fields(?action))]
 fn run_action(
     action: &Action,
-    child: &mut SupersChild,
-    tx: &Sender<CommandMsg>,
+    runtime: &mut ProgramRuntime,
+    tx: &Sender<CommandRequest>,
     program_config: &ProgramConfig,
     app_state: Arc<Mutex<ApplicationState>>,
 ) -> Result<(), SupersError> {
     debug!("processing action");
     match action {
         Action::ResetChild => {
-            *child = None;
+            runtime.child = None;
         }
         Action::SpawnChild => {
-            *child = Some(start_child_program(program_config)?);
+            if application_stopping(&app_state) {
+                debug!("not spawning child; the application is stopping");
+                runtime.child = None;
+                update_pgm_status(
+                    app_state,
+                    &program_config.name,
+                    ProgramStatus::Stopped,
+                );
+                return Ok(());
+            }
+            if !dependencies_completed(&app_state, program_config) {
+                debug!(deps = ?program_config.depends_on, "waiting for dependencies");
+                runtime.child = None;
+                runtime.waiting_for_deps = true;
+                update_pgm_status(
+                    app_state,
+                    &program_config.name,
+                    ProgramStatus::Stopped,
+                );
+                return Ok(());
+            }
+            if let Err(e) = hooks::run_hook(
+                program_config,
+                "pre_start",
+                &program_config.pre_start,
+            ) {
+                warn!(error = %e, "not spawning child");
+                runtime.child = None;
+                runtime.spawn_error = Some(e.to_string());
+                update_pgm_status(
+                    app_state.clone(),
+                    &program_config.name,
+                    ProgramStatus::Stopped,
+                );
+                return apply_policy(
+                    None,
+                    runtime,
+                    tx,
+                    program_config,
+                    &app_state,
+                );
+            }
+            runtime.oom_kills = oom_kills(program_config);
+            match start_child_program(program_config) {
+                Ok(child) => {
+                    runtime.child = Some(child.into());
+                    // Input pending for the previous child is not replayed.
+                    runtime.stdin_pending.clear();
+                }
+                Err(e) => {
+                    // Retrying would most likely fail the same way, so the
+                    // program is fatal until the next command.
+                    warn!(error = %e, "cannot spawn child");
+                    runtime.child = None;
+                    runtime.spawn_error = Some(e.to_string());
+                    publish_pgm_event(
+                        &app_state,
+                        &program_config.name,
+                        EventKind::Fatal {
+                            error: e.to_string(),
+                        },
+                    );
+                    update_pgm_fatal(
+                        app_state,
+                        &program_config.name,
+                        Some(e.to_string()),
+                    );
+                    return Ok(());
+                }
+            }
+            if let Some(child) = &runtime.child {
+                let pid = child.id();
+                update_pgm_finished(&app_state, &program_config.name, false);
+                update_pgm_history(&app_state, &program_config.name, |s| {
+                    s.record_start(pid, program_config.history_size)
+                });
+                publish_pgm_event(
+                    &app_state,
+                    &program_config.name,
+                    EventKind::Spawned { pid },
+                );
+            }
+            update_pgm_starts(app_state, &program_config.name);
+            hooks::run_hook(
+                program_config,
+                "post_start",
+                &program_config.post_start,
+            )
+            .unwrap_or_else(|e| warn!(error = %e, "post_start hook failed"));
         }
         Action::KillChild => {
-            child
+            publish_pgm_event(
+                &app_state,
+                &program_config.name,
+                EventKind::StopRequested,
+            );
+            hooks::run_hook(
+                program_config,
+                "pre_stop",
+                &program_config.pre_stop,
+            )
+            .unwrap_or_else(|e| warn!(error = %e, "pre_stop hook failed"));
+            let status = runtime
+                .child
                 .as_mut()
                 .map(|c| {
-                    c.kill().unwrap_or_else(|e| {
+                    let kill_mode = program_config.kill_mode;
+                    match kill_mode {
+                        KillMode::ProcessGroup => {
+                            process::kill_process_group(c.id())
+                        }
+                        KillMode::Process => c.kill(),
+                    }
+                    .unwrap_or_else(|e| {
                         warn!(
                             pid = c.id(),
                             error = ?e,
                             "process already exited"
                         );
                     });
+                    if let Some(cgroup) = Cgroup::of(program_config)
+                        .filter(|_| kill_mode == KillMode::ProcessGroup)
+                    {
+                        cgroup.kill_all().unwrap_or_else(|e| {
+                            warn!(error = ?e, "cannot kill processes in cgroup");
+                        });
+                    }
                     c.wait()
                 })
                 .unwrap_or_else(|| {
@@ -205,23 +1252,126 @@ fn run_action(
                         "Asked to kill non-existent child. This is a bug."
                     )
                 })?;
+            publish_pgm_event(
+                &app_state,
+                &program_config.name,
+                EventKind::Exited {
+                    status: status.to_string(),
+                    code: status.code(),
+                    requested: true,
+                },
+            );
+            update_pgm_history(&app_state, &program_config.name, |s| {
+                s.record_end(status, EndReason::Stopped)
+            });
+            hooks::run_hook(
+                program_config,
+                "post_stop",
+                &program_config.post_stop,
+            )
+            .unwrap_or_else(|e| warn!(error = %e, "post_stop hook failed"));
         }
-        Action::ApplyPolicy(code) => {
-            match program_config.restartpolicy {
-                RestartPolicy::Always => {
-                    // Under this policy, we **always** restart
-                    tx.send(CommandMsg::Start)?;
+        Action::SignalChild(signal) => {
+            if let Some(c) = &runtime.child {
+                debug!(pid = c.id(), signal, "signaling child");
+                process::send_signal(c.id(), *signal).unwrap_or_else(|e| {
+                    warn!(pid = c.id(), error = ?e, "cannot signal child");
+                });
+            }
+        }
+        Action::SignalAll(signal) => {
+            if let Some(c) = &runtime.child {
+                let kill_mode = program_config.kill_mode;
+                debug!(pid = c.id(), signal, ?kill_mode, "signaling children");
+                match kill_mode {
+                    KillMode::ProcessGroup => {
+                        process::signal_process_group(c.id(), *signal)
+                    }
+                    KillMode::Process => process::send_signal(c.id(), *signal),
                 }
-                RestartPolicy::Never => {
-                    // Do nothing, keep in `Exited` state.
+                .unwrap_or_else(|e| {
+                    warn!(pid = c.id(), error = ?e, "cannot signal child");
+                });
+                // A cgroup can only kill its processes.
+                if let Some(cgroup) = Cgroup::of(program_config).filter(|_| {
+                    kill_mode == KillMode::ProcessGroup
+                        && *signal == libc::SIGKILL
+                }) {
+                    cgroup.kill_all().unwrap_or_else(|e| {
+                        warn!(error = ?e, "cannot kill processes in cgroup");
+                    });
                 }
-                RestartPolicy::OnError => {
-                    // We restart if `code` is an error
-                    if !code.success() {
-                        tx.send(CommandMsg::Start)?;
-                    }
+            }
+        }
+        Action::WriteStdin(data) => {
+            let pending = runtime.stdin_pending.len();
+            match runtime.child.as_mut().and_then(|c| c.stdin()) {
+                Some(_) if pending + data.len() > STDIN_BUFFER_SIZE => {
+                    warn!(pending, "too much input pending; rejecting input");
+                    runtime.stdin_error = Some(format!(
+                        "{pending} bytes are waiting for the child to read them"
+                    ));
+                }
+                Some(stdin) => {
+                    runtime.stdin_pending.extend_from_slice(data);
+                    write_stdin(stdin, &mut runtime.stdin_pending);
                 }
+                None => {
+                    warn!("child has no stdin pipe; rejecting input");
+                    runtime.stdin_error = Some("the child has no stdin".into());
+                }
+            }
+        }
+        Action::ApplyPolicy(code) => {
+            apply_policy(Some(*code), runtime, tx, program_config, &app_state)?;
+        }
+        Action::RecordExit(status) => {
+            hooks::run_hook(
+                program_config,
+                "post_stop",
+                &program_config.post_stop,
+            )
+            .unwrap_or_else(|e| warn!(error = %e, "post_stop hook failed"));
+            let oom_kills =
+                oom_kills(program_config).saturating_sub(runtime.oom_kills);
+            if program_config.schedule.is_some() {
+                info!(%status, "scheduled run finished");
+            }
+            let reason = if oom_kills > 0 {
+                warn!(oom_kills, "child killed by the OOM killer");
+                ExitReason::OomKilled
+            } else {
+                ExitReason::Exited(*status)
+            };
+            if program_config.kind == ProgramKind::Oneshot {
+                let status = if status.success() {
+                    ProgramStatus::Completed
+                } else {
+                    ProgramStatus::Failed
+                };
+                update_pgm_status(
+                    app_state.clone(),
+                    &program_config.name,
+                    status,
+                );
             }
+            publish_pgm_event(
+                &app_state,
+                &program_config.name,
+                EventKind::Exited {
+                    status: reason.to_string(),
+                    code: status.code(),
+                    requested: false,
+                },
+            );
+            update_pgm_history(&app_state, &program_config.name, |s| {
+                let end = match reason {
+                    ExitReason::OomKilled => EndReason::OomKilled,
+                    ExitReason::Exited(_) => EndReason::Exited,
+                };
+                s.record_end(*status, end)
+            });
+            update_pgm_exit(app_state, &program_config.name, reason);
         }
         Action::UpdateStatus(status) => {
             update_pgm_status(app_state, &program_config.name, *status);
@@ -266,7 +1416,7 @@ fn get_child_status(
 /// started as well as a hashmap of the command channels created for each program in the App config.
 type ProgramControls = (
     Vec<JoinHandle<Result<(), SupersError>>>,
-    HashMap<String, Sender<CommandMsg>>,
+    HashMap<String, Sender<CommandRequest>>,
 );
 
 /// Main entrypoint for the programs.rs module; For each program in the app_config, this function:
@@ -283,22 +1433,42 @@ pub fn start_program_threads(
     debug!("starting threads for all programs");
     for program in app_config {
         debug!(program = program.name, "starting thread for program");
-        let (tx, rx) = unbounded::<CommandMsg>();
+        let (tx, rx) = unbounded::<CommandRequest>();
         {
             let program = program.clone();
             let program_name = program.name.clone();
             let tx = tx.clone();
             let app_state = app_state.clone();
+            // Listeners subscribe before any program starts, so that they
+            // get all the events.
+            let events = program
+                .listener
+                .as_ref()
+                .map(|_| app_state.lock().unwrap().events.subscribe());
             let handle = thread::Builder::new()
                 .name(program_name.clone())
                 .spawn(move || -> Result<(), SupersError> {
-                    run_state_machine_with_effects(&program, app_state, tx, rx)
+                    supervise(
+                        &program,
+                        app_state,
+                        tx,
+                        rx,
+                        events,
+                        run_state_machine_with_effects,
+                    )
                 })
                 .map_err(|e| {
                     SupersError::ProgramThreadStartError(program_name, e)
                 })?;
             handles.push(handle);
         }
+        if !program.watch.is_empty() {
+            handles.push(watch::start_watch_thread(
+                &program,
+                tx.clone(),
+                app_state.clone(),
+            )?);
+        }
         send_channels.insert(program.name.clone(), tx);
     }
 
@@ -308,16 +1478,38 @@ pub fn start_program_threads(
 #[cfg(test)]
 mod test {
     use crate::{
-        config::{ProgramConfig, RestartPolicy},
-        messages::CommandMsg,
-        programs::{run_action, state_machine_step, Action, ChildStatus},
-        state::ProgramStatus,
+        config::{
+            Backoff, Concurrency, HookConfig, KillMode, ProgramConfig,
+            ProgramKind, RestartPolicy, Rlimit, RlimitValue, Rlimits,
+            StdinMode,
+        },
+        errors::SupersError,
+        messages::CommandRequest,
+        messages::{CommandMsg, CommandReply},
+        programs::{
+            adopt_child, build_command, dependencies_completed, hand_over,
+            restart_on_error, run_action, run_actions,
+            run_state_machine_with_effects, start_child_program,
+            state_machine_step, supervise, wait_for_handover, Action,
+            ChildProcess, ChildStatus, ProgramRuntime, RunStateMachine,
+        },
+        state::ApplicationState,
+        state::{EndReason, ProgramStatus},
     };
     use anyhow::Result;
     use crossbeam::channel::unbounded;
     use std::{
-        process::Command,
-        sync::{Arc, Mutex},
+        collections::HashMap,
+        ffi::OsString,
+        fs, io,
+        os::unix::{ffi::OsStringExt, process::ExitStatusExt},
+        process::{Command, ExitStatus},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+        time::{Duration, SystemTime},
     };
     use test_log::test;
 
@@ -349,6 +1541,15 @@ mod test {
             &Some(CommandMsg::Stop),
         );
         assert!(s.contains(&Action::UpdateStatus(ProgramStatus::Stopped)));
+
+        // Signals only reach a running child
+        let hup = Some(CommandMsg::Signal(libc::SIGHUP));
+        let s = state_machine_step(&ChildStatus::Alive, &hup);
+        assert_eq!(s, vec![Action::SignalChild(libc::SIGHUP)]);
+        let s = state_machine_step(&ChildStatus::NoChild, &hup);
+        assert!(s.is_empty());
+        let s = state_machine_step(&ChildStatus::Exited(status), &hup);
+        assert_eq!(s, state_machine_step(&ChildStatus::Exited(status), &None));
         Ok(())
     }
 
@@ -358,37 +1559,31 @@ mod test {
         let s = Arc::new(Mutex::new(Default::default()));
         let (sx, rx) = unbounded();
 
-        let mut child = None;
-        run_action(&Action::ResetChild, &mut child, &sx, &p, s.clone())?;
-        assert!(child.is_none());
+        let mut rt = ProgramRuntime::default();
+        run_action(&Action::ResetChild, &mut rt, &sx, &p, s.clone())?;
+        assert!(rt.child.is_none());
 
-        let mut child = Some(Command::new("true").spawn()?);
-        run_action(&Action::ResetChild, &mut child, &sx, &p, s.clone())?;
-        assert!(child.is_none());
+        rt.child = Some(Command::new("true").spawn()?.into());
+        run_action(&Action::ResetChild, &mut rt, &sx, &p, s.clone())?;
+        assert!(rt.child.is_none());
 
         let p = ProgramConfig {
             name: "cat".into(),
             cmd: "cat".into(),
             ..Default::default()
         };
-        run_action(&Action::SpawnChild, &mut child, &sx, &p, s.clone())?;
-        assert!(child.is_some());
-        run_action(&Action::KillChild, &mut child, &sx, &p, s.clone())?;
-        run_action(&Action::KillChild, &mut child, &sx, &p, s.clone())?;
-        child.as_mut().unwrap().wait()?;
-        run_action(&Action::KillChild, &mut child, &sx, &p, s.clone())?;
+        run_action(&Action::SpawnChild, &mut rt, &sx, &p, s.clone())?;
+        assert!(rt.child.is_some());
+        run_action(&Action::KillChild, &mut rt, &sx, &p, s.clone())?;
+        run_action(&Action::KillChild, &mut rt, &sx, &p, s.clone())?;
+        rt.child.as_mut().unwrap().wait()?;
+        run_action(&Action::KillChild, &mut rt, &sx, &p, s.clone())?;
 
         let status = Command::new("true").spawn()?.wait()?;
-        run_action(
-            &Action::ApplyPolicy(status),
-            &mut child,
-            &sx,
-            &p,
-            s.clone(),
-        )?;
+        run_action(&Action::ApplyPolicy(status), &mut rt, &sx, &p, s.clone())?;
         // Default policy is restart always
         let resp = rx.recv()?;
-        assert_eq!(resp, CommandMsg::Start);
+        assert_eq!(resp.msg, CommandMsg::Start);
 
         let p = ProgramConfig {
             name: "cat".into(),
@@ -396,23 +1591,717 @@ mod test {
             restartpolicy: RestartPolicy::OnError,
             ..Default::default()
         };
-        run_action(
-            &Action::ApplyPolicy(status),
-            &mut child,
-            &sx,
-            &p,
-            s.clone(),
-        )?;
+        run_action(&Action::ApplyPolicy(status), &mut rt, &sx, &p, s.clone())?;
         // Should not restart on success
         let resp = rx.try_recv();
         assert!(resp.is_err());
 
         let status = Command::new("false").spawn()?.wait()?;
-        run_action(&Action::ApplyPolicy(status), &mut child, &sx, &p, s)?;
+        run_action(&Action::ApplyPolicy(status), &mut rt, &sx, &p, s.clone())?;
         // Should restart on error
         let resp = rx.recv()?;
-        assert_eq!(resp, CommandMsg::Start);
+        assert_eq!(resp.msg, CommandMsg::Start);
+
+        let a = s.lock().unwrap();
+        let events: Vec<_> = a.events.since(0).map(|e| e.kind.name()).collect();
+        assert_eq!(
+            events,
+            [
+                "spawned",
+                "stop_requested",
+                "exited",
+                "stop_requested",
+                "exited",
+                "stop_requested",
+                "exited",
+                "restart_scheduled",
+                "restart_scheduled"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_signal_child() -> Result<()> {
+        let s = Arc::new(Mutex::new(ApplicationState::default()));
+        let (sx, _rx) = unbounded();
+        let p = ProgramConfig {
+            name: "sleep".into(),
+            cmd: "sleep".into(),
+            args: vec!["30".into()],
+            ..Default::default()
+        };
+        let mut rt = ProgramRuntime::default();
+        run_action(&Action::SpawnChild, &mut rt, &sx, &p, s.clone())?;
+        let term = Action::SignalChild(libc::SIGTERM);
+        run_action(&term, &mut rt, &sx, &p, s.clone())?;
+        let status = rt.child.as_mut().unwrap().wait()?;
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+        Ok(())
+    }
 
+    #[test]
+    fn test_history() -> Result<()> {
+        let s = Arc::new(Mutex::new(ApplicationState::default()));
+        let (sx, rx) = unbounded();
+        let p = ProgramConfig {
+            name: "sleep".into(),
+            cmd: "sleep".into(),
+            args: vec!["30".into()],
+            history_size: 2,
+            ..Default::default()
+        };
+        let mut rt = ProgramRuntime::default();
+        let history = |s: &Arc<Mutex<ApplicationState>>| {
+            s.lock().unwrap().programs["sleep"].history.clone()
+        };
+        run_action(&Action::SpawnChild, &mut rt, &sx, &p, s.clone())?;
+        let pid = rt.child.as_ref().unwrap().id();
+        let runs = history(&s);
+        assert_eq!((runs.len(), runs[0].pid), (1, pid));
+        assert!(runs[0].ended.is_none());
+        run_action(&Action::KillChild, &mut rt, &sx, &p, s.clone())?;
+        let runs = history(&s);
+        assert!(runs[0].ended.is_some());
+        assert_eq!(runs[0].signal, Some(libc::SIGKILL));
+        assert_eq!(runs[0].reason, Some(EndReason::Stopped));
+
+        // An exit by itself, restarted by the policy
+        let p = ProgramConfig {
+            cmd: "false".into(),
+            args: vec![],
+            ..p
+        };
+        run_action(&Action::SpawnChild, &mut rt, &sx, &p, s.clone())?;
+        let status = rt.child.as_mut().unwrap().wait()?;
+        let actions = state_machine_step(&ChildStatus::Exited(status), &None);
+        run_actions(&actions, &mut rt, &sx, &p, s.clone())?;
+        assert_eq!(rx.try_recv()?.msg, CommandMsg::Start);
+        let runs = history(&s);
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[1].exit_code, Some(1));
+        assert_eq!(runs[1].reason, Some(EndReason::PolicyRestart));
+
+        // The history keeps the last `history_size` runs
+        run_action(&Action::SpawnChild, &mut rt, &sx, &p, s.clone())?;
+        rt.child.as_mut().unwrap().wait()?;
+        let runs = history(&s);
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0].reason, Some(EndReason::PolicyRestart));
+        assert!(runs[1].ended.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_handover() -> Result<()> {
+        let s = Arc::new(Mutex::new(ApplicationState::default()));
+        let (sx, _rx) = unbounded();
+        let p = ProgramConfig {
+            name: "sleep".into(),
+            cmd: "sleep".into(),
+            args: vec!["10".into()],
+            ..Default::default()
+        };
+        let mut rt = ProgramRuntime::default();
+        assert_eq!(
+            hand_over(&p, &mut rt, &sx, s.clone())?,
+            CommandReply::NotRunning
+        );
+        run_action(&Action::SpawnChild, &mut rt, &sx, &p, s.clone())?;
+        let pid = rt.child.as_ref().unwrap().id();
+        assert_eq!(
+            hand_over(&p, &mut rt, &sx, s.clone())?,
+            CommandReply::HandedOver(pid)
+        );
+        let handover = s.lock().unwrap().programs["sleep"].handover;
+        assert_eq!(handover.map(|h| h.pid), Some(pid));
+
+        // Commands that could replace the child wait for the handover to
+        // time out, while handover requests get the same answer
+        let (tx, rx) = unbounded();
+        tx.send(CommandMsg::Restart.into())?;
+        let (request, reply_rx) =
+            CommandRequest::with_reply(CommandMsg::Handover);
+        tx.send(request)?;
+        tx.send(CommandMsg::Stop.into())?;
+        let outcome = CommandReply::HandedOver(pid);
+        let held = wait_for_handover(&rx, &outcome, Duration::from_millis(100));
+        let held: Vec<_> = held.into_iter().map(|r| r.msg).collect();
+        assert_eq!(held, vec![CommandMsg::Restart, CommandMsg::Stop]);
+        assert_eq!(reply_rx.try_recv()?, outcome);
+
+        // The next instance adopts the child
+        let mut next = ProgramRuntime::default();
+        adopt_child(&p, &mut next, &s);
+        assert!(matches!(next.child, Some(ChildProcess::Adopted(_))));
+        assert_eq!(next.child.as_ref().unwrap().id(), pid);
+        let state = s.lock().unwrap().programs["sleep"].clone();
+        assert_eq!(state.status, ProgramStatus::Running);
+        assert!(state.handover.is_none());
+        run_action(&Action::KillChild, &mut next, &sx, &p, s.clone())?;
+        let runs = s.lock().unwrap().programs["sleep"].history.clone();
+        assert_eq!(runs[0].signal, Some(libc::SIGKILL));
+
+        // A child that cannot be adopted is lost
+        let mut other = ProgramRuntime::default();
+        run_action(&Action::SpawnChild, &mut other, &sx, &p, s.clone())?;
+        hand_over(&p, &mut other, &sx, s.clone())?;
+        let mut child = other.child.take().unwrap();
+        child.kill()?;
+        child.wait()?;
+        let mut next = ProgramRuntime::default();
+        adopt_child(&p, &mut next, &s);
+        assert!(next.child.is_none());
+        let runs = s.lock().unwrap().programs["sleep"].history.clone();
+        assert_eq!(runs[1].reason, Some(EndReason::Lost));
+
+        // Pipes do not survive the handover, so children with pipes stop
+        let p = ProgramConfig {
+            stdin: StdinMode::Pipe,
+            ..p
+        };
+        run_action(&Action::SpawnChild, &mut rt, &sx, &p, s.clone())?;
+        assert_eq!(
+            hand_over(&p, &mut rt, &sx, s.clone())?,
+            CommandReply::Stopped
+        );
+        assert!(rt.child.is_none());
         Ok(())
     }
+
+    // Return `true` if process `pid` exists and is not a zombie.
+    fn is_alive(pid: &str) -> bool {
+        fs::read_to_string(format!("/proc/{pid}/stat"))
+            .map(|s| !s.contains(") Z "))
+            .unwrap_or(false)
+    }
+
+    #[test]
+    fn test_kill_process_group() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let pid_file = temp_dir.path().join("pid");
+        let s = Arc::new(Mutex::new(Default::default()));
+        let (sx, _rx) = unbounded();
+        let term = Action::SignalAll(libc::SIGTERM);
+        for (action, kill_mode, grandchild_alive) in [
+            (&Action::KillChild, KillMode::ProcessGroup, false),
+            (&Action::KillChild, KillMode::Process, true),
+            (&term, KillMode::ProcessGroup, false),
+            (&term, KillMode::Process, true),
+        ] {
+            let p = ProgramConfig {
+                name: "sh".into(),
+                cmd: "/bin/sh".into(),
+                args: vec![
+                    "-c".into(),
+                    format!(
+                        "sleep 30 & echo $! > {}; wait",
+                        pid_file.display()
+                    ),
+                ],
+                kill_mode,
+                ..Default::default()
+            };
+            let _ = fs::remove_file(&pid_file);
+            let mut rt = ProgramRuntime::default();
+            run_action(&Action::SpawnChild, &mut rt, &sx, &p, s.clone())?;
+            let pid = loop {
+                match fs::read_to_string(&pid_file) {
+                    Ok(pid) if pid.ends_with('\n') => break pid,
+                    _ => thread::sleep(Duration::from_millis(10)),
+                }
+            };
+            let pid = pid.trim();
+            assert!(is_alive(pid));
+            run_action(action, &mut rt, &sx, &p, s.clone())?;
+            if let Some(child) = &mut rt.child {
+                child.wait()?;
+            }
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(is_alive(pid), grandchild_alive);
+            if grandchild_alive {
+                Command::new("kill").arg(pid).status()?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_command_non_utf8_env() -> Result<()> {
+        let name = format!("SUPERS_TEST_{}", uuid::Uuid::new_v4().simple());
+        std::env::set_var(&name, OsString::from_vec(b"caf\xe9".to_vec()));
+        let p = ProgramConfig {
+            name: "env".into(),
+            env: HashMap::from([("GREETING".into(), "hello".into())]),
+            ..Default::default()
+        };
+        for inherit_env in [true, false] {
+            let p = ProgramConfig {
+                inherit_env,
+                ..p.clone()
+            };
+            let output = build_command(&p, "env", &[])?.output()?;
+            assert!(output.status.success());
+            let vars: Vec<&[u8]> =
+                output.stdout.split(|b| *b == b'\n').collect();
+            let var = [name.as_bytes(), b"=caf\xe9"].concat();
+            assert_eq!(vars.contains(&var.as_slice()), inherit_env);
+            assert!(vars.contains(&b"GREETING=hello".as_slice()));
+        }
+        std::env::remove_var(&name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cgroup_attach_before_exec_settings() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let procs = root.path().join("cgroup.procs");
+        fs::write(&procs, "")?;
+        // With a file size limit of 0, writing `cgroup.procs` after the
+        // settings of the program were applied would fail.
+        let zero = Rlimit {
+            soft: RlimitValue::Limited(0),
+            hard: RlimitValue::Limited(0),
+        };
+        let p = ProgramConfig {
+            name: "true".into(),
+            cmd: "true".into(),
+            cgroup: Some(root.path().into()),
+            rlimits: Rlimits {
+                fsize: Some(zero),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut child = start_child_program(&p)?;
+        assert!(child.wait()?.success());
+        assert_eq!(fs::read_to_string(procs)?, "0");
+        Ok(())
+    }
+
+    #[test]
+    fn test_oom_kills_since_spawn() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let s = Arc::new(Mutex::new(ApplicationState::default()));
+        let (sx, _rx) = unbounded();
+        let p = ProgramConfig {
+            name: "api".into(),
+            cgroup: Some(root.path().into()),
+            ..Default::default()
+        };
+        let events = root.path().join("memory.events");
+        // The cgroup outlives the child killed by an earlier OOM
+        let mut rt = ProgramRuntime {
+            oom_kills: 2,
+            ..Default::default()
+        };
+        let exit = Action::RecordExit(ExitStatus::from_raw(9));
+        let last_reason = |s: &Arc<Mutex<ApplicationState>>| {
+            let a = s.lock().unwrap();
+            a.programs["api"].history.back().and_then(|r| r.reason)
+        };
+        for (oom_kills, reason) in
+            [(2, EndReason::Exited), (3, EndReason::OomKilled)]
+        {
+            fs::write(&events, format!("oom_kill {oom_kills}\n"))?;
+            s.lock()
+                .unwrap()
+                .programs
+                .entry("api".into())
+                .or_default()
+                .record_start(10, 5);
+            run_action(&exit, &mut rt, &sx, &p, s.clone())?;
+            assert_eq!(last_reason(&s), Some(reason));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_pre_start_blocks_spawn() -> Result<()> {
+        let s = Arc::new(Mutex::new(ApplicationState::default()));
+        let (sx, rx) = unbounded();
+        let p = ProgramConfig {
+            name: "cat".into(),
+            cmd: "cat".into(),
+            pre_start: Some(HookConfig {
+                cmd: "false".into(),
+                args: vec![],
+                timeout_secs: 5,
+            }),
+            backoff: Backoff {
+                initial_ms: 1000,
+                max_ms: 1500,
+            },
+            ..Default::default()
+        };
+        let mut rt = ProgramRuntime::default();
+        let actions = vec![
+            Action::SpawnChild,
+            Action::UpdateStatus(ProgramStatus::Running),
+        ];
+        for failures in 1..=3 {
+            run_actions(&actions, &mut rt, &sx, &p, s.clone())?;
+            assert!(rt.child.is_none());
+            assert_eq!(rt.failures, failures);
+            assert!(rt.restart_at.is_some());
+            assert_eq!(
+                s.lock().unwrap().programs["cat"].status,
+                ProgramStatus::Stopped
+            );
+        }
+        assert_eq!(p.backoff.delay(3), Duration::from_millis(1500));
+        // The restart is delayed, not sent right away
+        assert!(rx.try_recv().is_err());
+        // A command cancels the pending restart
+        assert_eq!(
+            rt.next_command(
+                Some(CommandMsg::Stop),
+                &ChildStatus::NoChild,
+                Concurrency::Skip
+            ),
+            Some(CommandMsg::Stop)
+        );
+        assert!(rt.restart_at.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reply_to() -> Result<()> {
+        let s = Arc::new(Mutex::new(ApplicationState::default()));
+        let (sx, _rx) = unbounded();
+        let p = ProgramConfig {
+            name: "missing".into(),
+            cmd: "/nonexistent/program".into(),
+            ..Default::default()
+        };
+        let mut rt = ProgramRuntime::default();
+        let actions = vec![
+            Action::SpawnChild,
+            Action::UpdateStatus(ProgramStatus::Running),
+        ];
+        // A failed spawn is reported instead of ending the program thread
+        run_actions(&actions, &mut rt, &sx, &p, s.clone())?;
+        assert!(rt.child.is_none());
+        assert!(matches!(
+            rt.reply_to(&CommandMsg::Start, &ChildStatus::NoChild),
+            CommandReply::SpawnError(_)
+        ));
+        assert_eq!(
+            s.lock().unwrap().programs["missing"].status,
+            ProgramStatus::Fatal
+        );
+        assert_eq!(
+            rt.reply_to(&CommandMsg::Stop, &ChildStatus::NoChild),
+            CommandReply::NotRunning
+        );
+        assert_eq!(
+            rt.reply_to(&CommandMsg::Start, &ChildStatus::Alive),
+            CommandReply::AlreadyRunning
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_supervise() -> Result<()> {
+        let s = Arc::new(Mutex::new(ApplicationState::default()));
+        let (tx, rx) = unbounded();
+        let p = ProgramConfig {
+            name: "api".into(),
+            ..Default::default()
+        };
+        // Fails, then panics, then handles one command and returns
+        let run: RunStateMachine = |_, _, _, _, rx| match STARTS
+            .fetch_add(1, Ordering::SeqCst)
+        {
+            0 => Err(SupersError::ProgramCheckProcessStatusError(
+                "api".into(),
+                io::Error::from_raw_os_error(10),
+            )),
+            1 => {
+                let _ = rx.recv();
+                panic!("boom")
+            }
+            _ => {
+                // A restart after a failure starts the program
+                let request = rx.recv().unwrap();
+                assert_eq!(request.msg, CommandMsg::Start);
+                let _ = request.reply.unwrap().send(CommandReply::Spawned(1));
+                Ok(())
+            }
+        };
+        static STARTS: AtomicUsize = AtomicUsize::new(0);
+        let handle = {
+            let (s, tx) = (s.clone(), tx.clone());
+            thread::spawn(move || supervise(&p, s, tx, rx, None, run))
+        };
+        let fatal = |s: &Arc<Mutex<ApplicationState>>| {
+            let a = s.lock().unwrap();
+            a.programs.get("api").and_then(|p| p.fatal_error.clone())
+        };
+        while fatal(&s).is_none() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            s.lock().unwrap().programs["api"].status,
+            ProgramStatus::Fatal
+        );
+        // Commands other than Start and Restart do not run the program again
+        let (request, reply) = CommandRequest::with_reply(CommandMsg::Stop);
+        tx.send(request)?;
+        assert_eq!(reply.recv()?, CommandReply::NotRunning);
+        tx.send(CommandMsg::Start.into())?;
+        while !fatal(&s).is_some_and(|e| e.contains("boom")) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let (request, reply) = CommandRequest::with_reply(CommandMsg::Restart);
+        tx.send(request)?;
+        assert_eq!(reply.recv()?, CommandReply::Spawned(1));
+        assert!(handle.join().unwrap().is_ok());
+        assert!(fatal(&s).is_none());
+        assert_eq!(STARTS.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_spawn_error() -> Result<()> {
+        let s = Arc::new(Mutex::new(ApplicationState::default()));
+        let events = s.lock().unwrap().events.subscribe();
+        let (tx, rx) = unbounded();
+        let p = ProgramConfig {
+            name: "typo".into(),
+            cmd: "/nonexistent/program".into(),
+            ..Default::default()
+        };
+        {
+            let (s, tx) = (s.clone(), tx.clone());
+            thread::spawn(move || {
+                supervise(&p, s, tx, rx, None, run_state_machine_with_effects)
+            });
+        }
+        let (request, reply) = CommandRequest::with_reply(CommandMsg::Start);
+        tx.send(request)?;
+        assert!(matches!(reply.recv()?, CommandReply::SpawnError(_)));
+        let state = s.lock().unwrap().programs["typo"].clone();
+        assert_eq!(state.status, ProgramStatus::Fatal);
+        assert!(state.fatal_error.is_some_and(|e| e.contains("typo")));
+        let event = events.recv_timeout(Duration::from_secs(5))?;
+        assert_eq!(event.kind.name(), "fatal");
+        Ok(())
+    }
+
+    #[test]
+    fn test_oneshot_and_dependencies() -> Result<()> {
+        let s = Arc::new(Mutex::new(ApplicationState::default()));
+        let (sx, rx) = unbounded();
+        let init = ProgramConfig {
+            name: "init".into(),
+            cmd: "false".into(),
+            kind: ProgramKind::Oneshot,
+            ..Default::default()
+        };
+        let web = ProgramConfig {
+            name: "web".into(),
+            cmd: "true".into(),
+            depends_on: vec!["init".into()],
+            ..Default::default()
+        };
+        let start = vec![
+            Action::SpawnChild,
+            Action::UpdateStatus(ProgramStatus::Running),
+        ];
+
+        // The dependent program waits for the oneshot program
+        let mut web_rt = ProgramRuntime::default();
+        run_actions(&start, &mut web_rt, &sx, &web, s.clone())?;
+        assert!(web_rt.child.is_none());
+        assert!(web_rt.waiting_for_deps);
+        assert_eq!(
+            s.lock().unwrap().programs["web"].status,
+            ProgramStatus::Stopped
+        );
+
+        // A failed run is reported as such, and restarted under `Always`
+        let mut rt = ProgramRuntime::default();
+        let mut run = |p: &ProgramConfig| -> Result<()> {
+            run_actions(&start, &mut rt, &sx, p, s.clone())?;
+            let status = rt.child.as_mut().unwrap().wait()?;
+            let actions =
+                state_machine_step(&ChildStatus::Exited(status), &None);
+            run_actions(&actions, &mut rt, &sx, p, s.clone())?;
+            Ok(())
+        };
+        run(&init)?;
+        assert_eq!(
+            s.lock().unwrap().programs["init"].status,
+            ProgramStatus::Failed
+        );
+        assert_eq!(s.lock().unwrap().programs["init"].exit_code, Some(1));
+        assert_eq!(rx.try_recv()?.msg, CommandMsg::Start);
+        assert!(!dependencies_completed(&s, &web));
+
+        // A successful run completes the program, which is not restarted
+        let init = ProgramConfig {
+            cmd: "true".into(),
+            ..init
+        };
+        run(&init)?;
+        assert_eq!(
+            s.lock().unwrap().programs["init"].status,
+            ProgramStatus::Completed
+        );
+        assert_eq!(s.lock().unwrap().programs["init"].exit_code, Some(0));
+        assert!(rx.try_recv().is_err());
+        assert!(dependencies_completed(&s, &web));
+        Ok(())
+    }
+
+    #[test]
+    fn test_restart_on_error() {
+        let exit = |code: i32| ExitStatus::from_raw(code << 8);
+        let killed = |signal: i32| ExitStatus::from_raw(signal);
+        let p = ProgramConfig::default();
+        assert!(!restart_on_error(exit(0), &p));
+        assert!(restart_on_error(exit(1), &p));
+        assert!(restart_on_error(killed(libc::SIGKILL), &p));
+
+        let p = ProgramConfig {
+            restart_on_exit_codes: vec![0, 3],
+            no_restart_on_exit_codes: vec![2],
+            restart_on_signals: vec!["SIGSEGV".into()],
+            ..Default::default()
+        };
+        assert!(restart_on_error(exit(0), &p));
+        assert!(!restart_on_error(exit(1), &p));
+        assert!(!restart_on_error(exit(2), &p));
+        assert!(restart_on_error(exit(3), &p));
+        assert!(!restart_on_error(killed(libc::SIGKILL), &p));
+        assert!(restart_on_error(killed(libc::SIGSEGV), &p));
+    }
+
+    #[test]
+    fn test_write_stdin() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let s = Arc::new(Mutex::new(ApplicationState::default()));
+        let (sx, _rx) = unbounded();
+        let p = ProgramConfig {
+            name: "head".into(),
+            cmd: "/bin/sh".into(),
+            args: vec!["-c".into(), "head -n 1 > out".into()],
+            cwd: Some(temp_dir.path().into()),
+            stdin: StdinMode::Pipe,
+            ..Default::default()
+        };
+        let mut rt = ProgramRuntime::default();
+        run_action(&Action::SpawnChild, &mut rt, &sx, &p, s.clone())?;
+        let write = Action::WriteStdin(b"hello\n".to_vec());
+        run_action(&write, &mut rt, &sx, &p, s.clone())?;
+        assert!(rt.child.as_mut().unwrap().wait()?.success());
+        assert_eq!(fs::read_to_string(temp_dir.path().join("out"))?, "hello\n");
+
+        // The pipe is re-created for the next child
+        run_action(&Action::SpawnChild, &mut rt, &sx, &p, s.clone())?;
+        assert!(rt.child.as_mut().unwrap().stdin().is_some());
+        run_action(&Action::KillChild, &mut rt, &sx, &p, s.clone())?;
+
+        // Input that does not fit in the pipe waits for the child to read it
+        let p = ProgramConfig {
+            args: vec!["-c".into(), "sleep 0.2; wc -c > out".into()],
+            ..p
+        };
+        run_action(&Action::SpawnChild, &mut rt, &sx, &p, s.clone())?;
+        let write = Action::WriteStdin(vec![b'x'; 512 * 1024]);
+        run_action(&write, &mut rt, &sx, &p, s.clone())?;
+        assert!(!rt.stdin_pending.is_empty());
+        let status = ChildStatus::Alive;
+        let msg = CommandMsg::Stdin(vec![]);
+        assert_eq!(rt.reply_to(&msg, &status), CommandReply::Delivered);
+        assert_eq!(format!("{write:?}"), "WriteStdin(524288 bytes)");
+        // Input beyond the buffer is rejected
+        let write = Action::WriteStdin(vec![b'x'; 1024 * 1024]);
+        run_action(&write, &mut rt, &sx, &p, s)?;
+        assert!(matches!(
+            rt.reply_to(&msg, &status),
+            CommandReply::Rejected(_)
+        ));
+        while !rt.stdin_pending.is_empty() {
+            rt.flush_stdin();
+            thread::sleep(Duration::from_millis(10));
+        }
+        if let Some(ChildProcess::Spawned(child)) = &mut rt.child {
+            drop(child.stdin.take());
+        }
+        assert!(rt.child.as_mut().unwrap().wait()?.success());
+        let out = fs::read_to_string(temp_dir.path().join("out"))?;
+        assert_eq!(out.trim(), "524288");
+        Ok(())
+    }
+
+    #[test]
+    fn test_scheduled_runs() {
+        let p = ProgramConfig {
+            schedule: Some("* * * * *".into()),
+            ..Default::default()
+        };
+        let mut rt = ProgramRuntime::new(&p);
+        assert!(rt.next_run.is_some());
+        let due = Some(SystemTime::now() - Duration::from_secs(1));
+        // Not due yet
+        assert_eq!(
+            rt.next_command(None, &ChildStatus::NoChild, Concurrency::Skip),
+            None
+        );
+
+        // A due run starts the program and moves to the next run
+        rt.next_run = due;
+        assert_eq!(
+            rt.next_command(None, &ChildStatus::NoChild, Concurrency::Skip),
+            Some(CommandMsg::Start)
+        );
+        assert!(rt.next_run > due);
+
+        // Overlapping runs are skipped...
+        rt.next_run = due;
+        assert_eq!(
+            rt.next_command(None, &ChildStatus::Alive, Concurrency::Skip),
+            None
+        );
+        assert_eq!(
+            rt.next_command(None, &ChildStatus::NoChild, Concurrency::Skip),
+            None
+        );
+
+        // ...or queued until the previous run finishes
+        rt.next_run = due;
+        assert_eq!(
+            rt.next_command(None, &ChildStatus::Alive, Concurrency::Queue),
+            None
+        );
+        assert_eq!(rt.queued_runs, 1);
+        assert_eq!(
+            rt.next_command(None, &ChildStatus::NoChild, Concurrency::Queue),
+            Some(CommandMsg::Start)
+        );
+        assert_eq!(rt.queued_runs, 0);
+
+        // Stop pauses the schedule until the next Start
+        rt.next_command(
+            Some(CommandMsg::Stop),
+            &ChildStatus::NoChild,
+            Concurrency::Skip,
+        );
+        rt.next_run = due;
+        assert_eq!(
+            rt.next_command(None, &ChildStatus::NoChild, Concurrency::Skip),
+            None
+        );
+        rt.next_command(
+            Some(CommandMsg::Start),
+            &ChildStatus::NoChild,
+            Concurrency::Skip,
+        );
+        assert_eq!(
+            rt.next_command(None, &ChildStatus::NoChild, Concurrency::Skip),
+            Some(CommandMsg::Start)
+        );
+    }
 }
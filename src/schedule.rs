@@ -0,0 +1,209 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Cron-style schedules
+// ====================
+
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+// Number of years to look ahead for the next run before giving up, to
+// terminate on schedules that never fire (e.g., "0 0 30 2 *").
+const LOOKAHEAD_YEARS: u64 = 5;
+
+/// A schedule in the standard 5-field cron format:
+/// `minute hour day-of-month month day-of-week`, evaluated in UTC.
+///
+/// Each field accepts `*`, single values, ranges (`a-b`), steps (`*/n` and
+/// `a-b/n`) and comma-separated lists of those. Day-of-week goes from 0
+/// (Sunday) to 6, with 7 also meaning Sunday. As in cron, when both
+/// day-of-month and day-of-week are restricted, a day matching either runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Whether the day-of-month and day-of-week fields are not `*`.
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+// Parse a cron field into a bit set of the values in `min..=max`.
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, String> {
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step = step
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|s| *s > 0)
+                    .ok_or_else(|| format!("invalid step in {part:?}"))?;
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let value = |v: &str| {
+            v.parse::<u64>()
+                .ok()
+                .filter(|v| (min..=max).contains(v))
+                .ok_or_else(|| format!("value {v:?} not in {min}-{max}"))
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((a, b)) => (value(a)?, value(b)?),
+                // `a/n` means from `a` to the end of the range.
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            },
+        };
+        if start > end {
+            return Err(format!("empty range {range:?}"));
+        }
+        for v in (start..=end).step_by(step as usize) {
+            set |= 1 << v;
+        }
+    }
+    Ok(set)
+}
+
+// Convert days since the Unix epoch into a (year, month, day) date.
+// From Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u64, u64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u64;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u64;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Format `t` as an RFC 3339 timestamp in UTC, with second precision.
+pub fn format_utc(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+impl Schedule {
+    /// Parse a schedule in the 5-field cron format.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("expected 5 fields in schedule {s:?}"));
+        };
+        let mut weekdays_set = parse_field(weekdays, 0, 7)?;
+        if weekdays_set & (1 << 7) != 0 {
+            weekdays_set |= 1;
+        }
+        Ok(Schedule {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekdays_set,
+            days_restricted: days != "*",
+            weekdays_restricted: weekdays != "*",
+        })
+    }
+
+    // Whether the schedule runs on the day `day` of the month, which is
+    // weekday `weekday`.
+    fn runs_on(&self, day: u64, weekday: u64) -> bool {
+        let day_matches = self.days & (1 << day) != 0;
+        let weekday_matches = self.weekdays & (1 << weekday) != 0;
+        if self.days_restricted && self.weekdays_restricted {
+            day_matches || weekday_matches
+        } else {
+            day_matches && weekday_matches
+        }
+    }
+
+    /// The first time the schedule fires strictly after `after`, or `None` if
+    /// it does not fire in the next few years.
+    pub fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        let secs = after.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        // Minutes since the epoch
+        let mut minute = secs / 60 + 1;
+        let limit = minute + LOOKAHEAD_YEARS * 366 * MINUTES_PER_DAY;
+        while minute < limit {
+            let days = minute / MINUTES_PER_DAY;
+            let (_, month, day) = civil_from_days(days as i64);
+            // 1970-01-01 was a Thursday.
+            let weekday = (days + 4) % 7;
+            if self.months & (1 << month) == 0 || !self.runs_on(day, weekday) {
+                minute = (days + 1) * MINUTES_PER_DAY;
+                continue;
+            }
+            if self.hours & (1 << (minute % MINUTES_PER_DAY / 60)) == 0 {
+                minute = (minute / 60 + 1) * 60;
+                continue;
+            }
+            if self.minutes & (1 << (minute % 60)) == 0 {
+                minute += 1;
+                continue;
+            }
+            return Some(UNIX_EPOCH + Duration::from_secs(minute * 60));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_utc, Schedule};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    // 2023-01-02T03:04:05Z, a Monday
+    fn t0() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_672_628_645)
+    }
+
+    fn next(schedule: &str) -> String {
+        let s = Schedule::parse(schedule).unwrap();
+        format_utc(s.next_after(t0()).unwrap())
+    }
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(format_utc(t0()), "2023-01-02T03:04:05Z");
+    }
+
+    #[test]
+    fn test_next_after() {
+        assert_eq!(next("* * * * *"), "2023-01-02T03:05:00Z");
+        assert_eq!(next("*/5 * * * *"), "2023-01-02T03:05:00Z");
+        assert_eq!(next("0 * * * *"), "2023-01-02T04:00:00Z");
+        assert_eq!(next("30 2 * * *"), "2023-01-03T02:30:00Z");
+        assert_eq!(next("0 0 1 * *"), "2023-02-01T00:00:00Z");
+        assert_eq!(next("0 12 * * 0"), "2023-01-08T12:00:00Z");
+        assert_eq!(next("0 12 * * 7"), "2023-01-08T12:00:00Z");
+        assert_eq!(next("0 0 29 2 *"), "2024-02-29T00:00:00Z");
+        assert_eq!(next("15,45 9-17/4 * * 1-5"), "2023-01-02T09:15:00Z");
+        // Day-of-month or day-of-week when both are restricted
+        assert_eq!(next("0 0 15 * 3"), "2023-01-04T00:00:00Z");
+        let never = Schedule::parse("0 0 30 2 *").unwrap();
+        assert!(never.next_after(t0()).is_none());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Schedule::parse("* * * *").is_err());
+        assert!(Schedule::parse("60 * * * *").is_err());
+        assert!(Schedule::parse("*/0 * * * *").is_err());
+        assert!(Schedule::parse("5-1 * * * *").is_err());
+        assert!(Schedule::parse("a * * * *").is_err());
+    }
+}
@@ -1,9 +1,27 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    os::unix::process::ExitStatusExt,
+    process::ExitStatus,
+    time::SystemTime,
+};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use serde_derive::{Deserialize, Serialize};
+
+use crate::events::EventBus;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum ProgramStatus {
     Running,
+    #[default]
     Stopped,
+    /// A oneshot program ran to completion successfully
+    Completed,
+    /// A oneshot program exited with a non-success status
+    Failed,
+    /// The thread running the program failed; the program is not supervised
+    /// until it is started again
+    Fatal,
 }
 
 impl Display for ProgramStatus {
@@ -12,10 +30,157 @@ impl Display for ProgramStatus {
     }
 }
 
+/// Reason why the last child process of a program exited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The process exited by itself, or was killed by a signal
+    Exited(ExitStatus),
+    /// The process was killed by the OOM killer of its cgroup
+    OomKilled,
+}
+
+impl Display for ExitReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitReason::Exited(status) => write!(f, "{}", status),
+            ExitReason::OomKilled => write!(f, "killed by the OOM killer"),
+        }
+    }
+}
+
+/// Why a run of a program ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EndReason {
+    /// The child exited by itself
+    Exited,
+    /// The child exited by itself, and the restart policy restarted it
+    PolicyRestart,
+    /// The child was stopped by a command, e.g., from the API
+    Stopped,
+    /// The child was killed by the OOM killer of its cgroup
+    OomKilled,
+    /// supers exited while the child was running
+    Lost,
+}
+
+impl Display for EndReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EndReason::Exited => write!(f, "exited"),
+            EndReason::PolicyRestart => {
+                write!(f, "exited and restarted by the policy")
+            }
+            EndReason::Stopped => write!(f, "stopped"),
+            EndReason::OomKilled => write!(f, "killed by the OOM killer"),
+            EndReason::Lost => write!(f, "supers exited while it was running"),
+        }
+    }
+}
+
+/// A run of a program, from the spawn of its child to its exit
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub pid: u32,
+    pub started: SystemTime,
+    /// When the child exited; `None` while it runs
+    pub ended: Option<SystemTime>,
+    /// Exit code of the child, if it exited by itself
+    pub exit_code: Option<i32>,
+    /// Signal that killed the child, if any
+    pub signal: Option<i32>,
+    pub reason: Option<EndReason>,
+}
+
+/// A child handed over to the next instance of supers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HandoverChild {
+    pub pid: u32,
+    /// Start time of the child, in clock ticks after boot, telling it apart
+    /// from a process that reuses its pid
+    pub start_ticks: u64,
+}
+
+/// State of a program as seen by the administrative API
+#[derive(Debug, Clone, Default)]
+pub struct ProgramState {
+    pub status: ProgramStatus,
+    pub last_exit: Option<ExitReason>,
+    /// Exit code of the last child, if it exited by itself
+    pub exit_code: Option<i32>,
+    /// Number of times a child of the program was spawned
+    pub starts: u64,
+    /// Next run of a scheduled program
+    pub next_run: Option<SystemTime>,
+    /// Reason why the thread running the program failed, if it did
+    pub fatal_error: Option<String>,
+    /// Last runs of the program, oldest first
+    pub history: VecDeque<RunRecord>,
+    /// Whether an operator stopped the program; it stays stopped when supers
+    /// restarts
+    pub stopped_by_operator: bool,
+    /// Child handed over to the next instance of supers, which adopts it
+    pub handover: Option<HandoverChild>,
+    /// Whether the program exited and does not run again without a command
+    pub finished: bool,
+}
+
+impl ProgramState {
+    /// Record a new run with child `pid`, keeping the last `capacity` runs.
+    pub fn record_start(&mut self, pid: u32, capacity: usize) {
+        while !self.history.is_empty() && self.history.len() >= capacity {
+            self.history.pop_front();
+        }
+        if capacity > 0 {
+            self.history.push_back(RunRecord {
+                pid,
+                started: SystemTime::now(),
+                ended: None,
+                exit_code: None,
+                signal: None,
+                reason: None,
+            });
+        }
+    }
+
+    /// Record the end of the current run, if any, with `status`.
+    pub fn record_end(&mut self, status: ExitStatus, reason: EndReason) {
+        if let Some(run) = self.history.back_mut().filter(|r| r.ended.is_none())
+        {
+            run.ended = Some(SystemTime::now());
+            run.exit_code = status.code();
+            run.signal = status.signal();
+            run.reason = Some(reason);
+        }
+    }
+
+    /// Record that the current run, if any, was lost by supers.
+    pub fn record_lost(&mut self) {
+        if let Some(run) = self.history.back_mut().filter(|r| r.ended.is_none())
+        {
+            run.reason = Some(EndReason::Lost);
+        }
+    }
+
+    /// Record that the restart policy restarted the program after its last
+    /// run exited by itself.
+    pub fn record_policy_restart(&mut self) {
+        if let Some(run) = self
+            .history
+            .back_mut()
+            .filter(|r| r.reason == Some(EndReason::Exited))
+        {
+            run.reason = Some(EndReason::PolicyRestart);
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub enum ApplicationStatus {
     #[default]
     Running,
+    /// A termination signal stops the application; programs do not start
+    /// anymore
+    Stopping,
     // TODO -- uncomment when implementing the app stop endpoint
     // Stopped,
 }
@@ -29,5 +194,7 @@ impl Display for ApplicationStatus {
 #[derive(Debug, Default)]
 pub struct ApplicationState {
     pub application_status: ApplicationStatus,
-    pub programs: HashMap<String, ProgramStatus>,
+    pub programs: HashMap<String, ProgramState>,
+    /// Lifecycle transitions of the programs
+    pub events: EventBus,
 }
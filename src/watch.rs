@@ -0,0 +1,241 @@
+use std::{
+    env,
+    path::{Component, Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::Sender;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use notify::{RecursiveMode, Watcher};
+use tracing::{debug, info, instrument, warn};
+
+use crate::{
+    config::ProgramConfig,
+    errors::SupersError,
+    messages::{CommandMsg, CommandRequest},
+    state::{ApplicationState, ProgramStatus},
+};
+
+// Watched files
+// =============
+
+// Make `pattern` absolute, relative to the working directory of supers, and
+// drop its `.` components.
+fn absolute(pattern: &str) -> PathBuf {
+    let cwd = env::current_dir().unwrap_or_default();
+    cwd.join(pattern)
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .collect()
+}
+
+fn has_glob(s: &str) -> bool {
+    s.contains(['*', '?', '[', '{'])
+}
+
+// Split `path` into the directory to watch and whether to watch it
+// recursively: plain paths watch their parent, and globs watch the directory
+// before their first glob component, recursively if a directory is a glob.
+fn watch_root(path: &Path) -> (PathBuf, RecursiveMode) {
+    let components: Vec<_> = path.components().collect();
+    let first_glob = components
+        .iter()
+        .position(|c| has_glob(&c.as_os_str().to_string_lossy()))
+        .unwrap_or(components.len() - 1);
+    let root = components[..first_glob].iter().collect();
+    let mode = if first_glob < components.len() - 1 {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
+    (root, mode)
+}
+
+/// The paths watched by program `p`, as a glob set and the directories to
+/// watch.
+pub fn watched_paths(
+    p: &ProgramConfig,
+) -> Result<(GlobSet, Vec<(PathBuf, RecursiveMode)>), SupersError> {
+    let mut globs = GlobSetBuilder::new();
+    let mut roots = vec![];
+    for pattern in &p.watch {
+        let path = absolute(pattern);
+        let glob = Glob::new(&path.to_string_lossy()).map_err(|e| {
+            SupersError::ProgramWatchError(
+                p.name.clone(),
+                format!("invalid pattern {pattern}: {e}"),
+            )
+        })?;
+        globs.add(glob);
+        roots.push(watch_root(&path));
+    }
+    let globs = globs.build().map_err(|e| {
+        SupersError::ProgramWatchError(p.name.clone(), e.to_string())
+    })?;
+    Ok((globs, roots))
+}
+
+/// Start a thread that restarts program `p` through `tx` when the files in its
+/// `watch` list change, while the program is running. Changes are debounced:
+/// the restart happens once no change was seen for `watch_debounce_ms`.
+#[instrument(level = "debug", skip_all, fields(program = p.name))]
+pub fn start_watch_thread(
+    p: &ProgramConfig,
+    tx: Sender<CommandRequest>,
+    app_state: Arc<Mutex<ApplicationState>>,
+) -> Result<JoinHandle<Result<(), SupersError>>, SupersError> {
+    let err = |e: notify::Error| {
+        SupersError::ProgramWatchError(p.name.clone(), e.to_string())
+    };
+    let (globs, roots) = watched_paths(p)?;
+    let (events_tx, events_rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(events_tx).map_err(err)?;
+    for (root, mode) in roots {
+        debug!(?root, ?mode, "watching directory");
+        if let Err(e) = watcher.watch(&root, mode) {
+            warn!(?root, error = %e, "cannot watch directory");
+        }
+    }
+    let name = p.name.clone();
+    let debounce = Duration::from_millis(p.watch_debounce_ms);
+    thread::Builder::new()
+        .name(format!("{name}-watch"))
+        .spawn(move || {
+            // The watcher stops when dropped, so the thread owns it.
+            let _watcher = watcher;
+            debounce_changes(
+                &name, &globs, debounce, &events_rx, &tx, &app_state,
+            )
+        })
+        .map_err(|e| SupersError::ProgramThreadStartError(p.name.clone(), e))
+}
+
+type Events = mpsc::Receiver<notify::Result<notify::Event>>;
+
+// Restart program `name` once no change to the paths in `globs` was seen for
+// `debounce`, if the program is running. Return when the watcher stops.
+fn debounce_changes(
+    name: &str,
+    globs: &GlobSet,
+    debounce: Duration,
+    events: &Events,
+    tx: &Sender<CommandRequest>,
+    app_state: &Arc<Mutex<ApplicationState>>,
+) -> Result<(), SupersError> {
+    let mut restart_at: Option<Instant> = None;
+    loop {
+        let timeout = restart_at
+            .map(|at| at.saturating_duration_since(Instant::now()))
+            .unwrap_or(Duration::MAX);
+        match events.recv_timeout(timeout) {
+            Ok(Ok(event)) => {
+                if event.paths.iter().any(|p| globs.is_match(p)) {
+                    debug!(paths = ?event.paths, "watched path changed");
+                    restart_at = Some(Instant::now() + debounce);
+                }
+            }
+            Ok(Err(e)) => warn!(error = %e, "error watching files"),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                restart_at = None;
+                let a = app_state.lock().unwrap();
+                let status = a.programs.get(name).map(|s| s.status);
+                if status == Some(ProgramStatus::Running) {
+                    info!(program = name, "watched paths changed; restarting");
+                    tx.send(CommandMsg::Restart.into())?;
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{start_watch_thread, watch_root, watched_paths};
+    use crate::{
+        config::ProgramConfig,
+        state::{ApplicationState, ProgramState, ProgramStatus},
+    };
+    use anyhow::Result;
+    use crossbeam::channel::unbounded;
+    use notify::RecursiveMode;
+    use std::{
+        collections::HashMap,
+        fs,
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[test]
+    fn test_watch_root() {
+        let root = |p| watch_root(Path::new(p));
+        assert_eq!(
+            root("/app/target/debug/api"),
+            (
+                PathBuf::from("/app/target/debug"),
+                RecursiveMode::NonRecursive
+            )
+        );
+        assert_eq!(
+            root("/app/config/*.yaml"),
+            (PathBuf::from("/app/config"), RecursiveMode::NonRecursive)
+        );
+        assert_eq!(
+            root("/app/src/**/*.rs"),
+            (PathBuf::from("/app/src"), RecursiveMode::Recursive)
+        );
+    }
+
+    #[test]
+    fn test_watched_paths() -> Result<()> {
+        let p = ProgramConfig {
+            watch: vec!["./config/*.yaml".into()],
+            ..Default::default()
+        };
+        let (globs, _) = watched_paths(&p)?;
+        let cwd = std::env::current_dir()?;
+        assert!(globs.is_match(cwd.join("config/app.yaml")));
+        assert!(!globs.is_match(cwd.join("config/app.toml")));
+
+        let p = ProgramConfig {
+            watch: vec!["[".into()],
+            ..Default::default()
+        };
+        assert!(watched_paths(&p).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_restart_on_change() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let p = ProgramConfig {
+            name: "api".into(),
+            watch: vec![format!("{}/*.yaml", dir.path().display())],
+            watch_debounce_ms: 100,
+            ..Default::default()
+        };
+        let state = ProgramState {
+            status: ProgramStatus::Running,
+            ..Default::default()
+        };
+        let app_state = Arc::new(Mutex::new(ApplicationState {
+            programs: HashMap::from([("api".into(), state)]),
+            ..Default::default()
+        }));
+        let (tx, rx) = unbounded();
+        let _handle = start_watch_thread(&p, tx, app_state)?;
+
+        fs::write(dir.path().join("other.txt"), "x")?;
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+        // Several changes in a row restart the program once
+        for _ in 0..3 {
+            fs::write(dir.path().join("app.yaml"), "x")?;
+        }
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+        Ok(())
+    }
+}